@@ -334,6 +334,9 @@ pub enum LoadBalancingAlgorithms {
   RoundRobin,
   Random,
   LeastConnections,
+  WeightedRandom,
+  /// the first backend that can be used, in configuration order
+  ConfigOrder,
 }
 
 impl Default for LoadBalancingAlgorithms {
@@ -368,6 +371,8 @@ impl FromStr for LoadBalancingAlgorithms {
     match s {
       "roundrobin" => Ok(LoadBalancingAlgorithms::RoundRobin),
       "random" => Ok(LoadBalancingAlgorithms::Random),
+      "weightedrandom" => Ok(LoadBalancingAlgorithms::WeightedRandom),
+      "configorder" => Ok(LoadBalancingAlgorithms::ConfigOrder),
       _ => Err(ParseErrorLoadBalancing{}),
     }
   }
@@ -379,12 +384,51 @@ impl FromStr for LoadBalancingAlgorithms {
 pub struct BackendConfig {
   pub address: SocketAddr,
   pub weight: Option<u8>,
+  /// share of the app's traffic, in percent. When one of the app's
+  /// backends has it, it replaces `weight` for all of them. Only the
+  /// weightedrandom policy uses it
+  pub weight_percentage: Option<u8>,
   pub sticky_id: Option<String>,
   pub backup: Option<bool>,
 }
 
+/// how far from 100 the sum of an app's weight percentages can be
+/// before we warn about it
+pub const WEIGHT_PERCENTAGE_TOLERANCE: u32 = 1;
+
+/// computes the load balancing weight of each backend of an app. Percentages
+/// are normalized so that they always sum to 100
+pub fn backend_weights(app_id: &str, backends: &[BackendConfig]) -> Vec<u8> {
+  if backends.iter().all(|backend| backend.weight_percentage.is_none()) {
+    return backends.iter().map(|backend| backend.weight.unwrap_or(100)).collect();
+  }
+
+  for backend in backends.iter().filter(|backend| backend.weight_percentage.is_none()) {
+    warn!("backend {} of app {} has no weight percentage, it will not get any traffic", backend.address, app_id);
+  }
+
+  let total: u32 = backends.iter().map(|backend| backend.weight_percentage.unwrap_or(0) as u32).sum();
+  if total < 100 - WEIGHT_PERCENTAGE_TOLERANCE || total > 100 + WEIGHT_PERCENTAGE_TOLERANCE {
+    warn!("weight percentages of app {} sum to {}%, they will be scaled to 100%", app_id, total);
+  }
+
+  if total == 0 {
+    return backends.iter().map(|_| 0).collect();
+  }
+
+  backends.iter().map(|backend| {
+    let percentage = backend.weight_percentage.unwrap_or(0) as u32;
+    ((percentage * 100 + total / 2) / total) as u8
+  }).collect()
+}
+
 impl FileAppConfig {
   pub fn to_app_config(self, app_id: &str, expect_proxy: &HashSet<SocketAddr>) -> Result<AppConfig, String> {
+    if self.load_balancing_policy != LoadBalancingAlgorithms::WeightedRandom
+      && self.backends.iter().any(|backend| backend.weight_percentage.is_some()) {
+      return Err(format!("application {} uses weight_percentage, which requires load_balancing_policy = \"weightedrandom\"", app_id));
+    }
+
     match self.protocol {
       FileAppProtocolConfig::Tcp => {
         let mut has_expect_proxy = None;
@@ -532,10 +576,11 @@ impl HttpAppConfig {
       v.extend(orders.drain(..));
     }
 
+    let weights = backend_weights(&self.app_id, &self.backends);
     let mut backend_count = 0usize;
-    for backend in &self.backends {
+    for (backend, weight) in self.backends.iter().zip(weights) {
         let load_balancing_parameters = Some(LoadBalancingParams {
-          weight: backend.weight.unwrap_or(100),
+          weight,
         });
 
         v.push(ProxyRequestData::AddBackend(Backend {
@@ -589,10 +634,11 @@ impl TcpAppConfig {
       }));
     }
 
+    let weights = backend_weights(&self.app_id, &self.backends);
     let mut backend_count = 0usize;
-    for backend in &self.backends {
+    for (backend, weight) in self.backends.iter().zip(weights) {
       let load_balancing_parameters = Some(LoadBalancingParams {
-        weight: backend.weight.unwrap_or(100),
+        weight,
       });
 
       v.push(ProxyRequestData::AddBackend(Backend {
@@ -1176,4 +1222,46 @@ mod tests {
     println!("config: {:#?}", config);
     //panic!();
   }
+
+  fn percentage_backend(port: u16, weight_percentage: Option<u8>) -> BackendConfig {
+    BackendConfig {
+      address: SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+      weight: None,
+      weight_percentage,
+      sticky_id: None,
+      backup: None,
+    }
+  }
+
+  #[test]
+  fn backend_weights_from_percentages() {
+    let backends = vec![percentage_backend(1024, None), percentage_backend(1025, None)];
+    assert_eq!(vec![100, 100], backend_weights("app_1", &backends));
+
+    let backends = vec![percentage_backend(1024, Some(70)), percentage_backend(1025, Some(30))];
+    assert_eq!(vec![70, 30], backend_weights("app_1", &backends));
+
+    let backends = vec![percentage_backend(1024, Some(35)), percentage_backend(1025, Some(15))];
+    assert_eq!(vec![70, 30], backend_weights("app_1", &backends));
+
+    let backends = vec![percentage_backend(1024, Some(70)), percentage_backend(1025, None)];
+    assert_eq!(vec![100, 0], backend_weights("app_1", &backends));
+  }
+
+  #[test]
+  fn weight_percentages_require_weighted_random() {
+    let app = |load_balancing_policy| FileAppConfig {
+      frontends:             Vec::new(),
+      backends:              vec![percentage_backend(1024, Some(70)), percentage_backend(1025, Some(30))],
+      protocol:              FileAppProtocolConfig::Tcp,
+      sticky_session:        None,
+      https_redirect:        None,
+      send_proxy:            None,
+      load_balancing_policy,
+      answer_503:            None,
+    };
+
+    assert!(app(LoadBalancingAlgorithms::RoundRobin).to_app_config("app_1", &HashSet::new()).is_err());
+    assert!(app(LoadBalancingAlgorithms::WeightedRandom).to_app_config("app_1", &HashSet::new()).is_ok());
+  }
 }
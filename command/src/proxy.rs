@@ -658,6 +658,29 @@ mod tests {
     }));
   }
 
+  #[test]
+  fn add_ipv6_backend_test() {
+    let raw_json = r#"{"type": "ADD_BACKEND", "data": {"app_id": "xxx", "backend_id": "xxx-0", "address": "[::1]:8080"}}"#;
+    let command: ProxyRequestData = serde_json::from_str(raw_json).expect("could not parse json");
+    println!("{:?}", command);
+    assert!(command == ProxyRequestData::AddBackend(Backend{
+      app_id: String::from("xxx"),
+      backend_id: String::from("xxx-0"),
+      address: "[::1]:8080".parse().unwrap(),
+      sticky_id: None,
+      load_balancing_parameters: None,
+      backup: None,
+    }));
+  }
+
+  #[test]
+  fn add_backend_with_malformed_address_test() {
+    for address in &["::1:8080", "127.0.0.1", "127.0.0.1:99999", "localhost:8080"] {
+      let raw_json = format!(r#"{{"type": "ADD_BACKEND", "data": {{"app_id": "xxx", "backend_id": "xxx-0", "address": "{}"}}}}"#, address);
+      assert!(serde_json::from_str::<ProxyRequestData>(&raw_json).is_err(), "address {} should be rejected", address);
+    }
+  }
+
   #[test]
   fn remove_backend_test() {
     let raw_json = r#"{"type": "REMOVE_BACKEND", "data": {"app_id": "xxx", "backend_id": "xxx-0", "address": "0.0.0.0:8080"}}"#;
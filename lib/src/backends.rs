@@ -1,334 +1,3216 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::io::{self,Write};
+use std::fmt;
+use std::time::{Duration,Instant};
 use mio::net::TcpStream;
 
-use sozu_command::{proxy, config::LoadBalancingAlgorithms};
+use sozu_command::{proxy, proxy::LoadBalancingParams, config::LoadBalancingAlgorithms};
 
-use super::{AppId,Backend,ConnectionError,load_balancing::*};
+use super::{AppId,Backend,BackendConnectionStatus,BackendStatus,CircuitBreakerState,ConnectionError,load_balancing::*};
 use server::push_event;
+use retry::RetryPolicy;
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MetricsFormat {
+  Prometheus,
+  Json,
+}
+
+type BackendMetric = (&'static str, &'static str, fn(&Backend, Instant) -> usize);
+
+const BACKEND_METRICS: [BackendMetric; 9] = [
+  ("sozu_backend_active_connections",            "gauge",   metric_active_connections),
+  ("sozu_backend_connections_opened",            "counter", metric_opened_connections),
+  ("sozu_backend_connections_closed",            "counter", metric_closed_connections),
+  ("sozu_backend_connections_opened_per_minute", "gauge",   metric_opened_per_minute),
+  ("sozu_backend_connections_closed_per_minute", "gauge",   metric_closed_per_minute),
+  ("sozu_backend_failures",                      "counter", metric_failures),
+  ("sozu_backend_available",                     "gauge",   metric_available),
+  ("sozu_backend_flapping",                      "gauge",   metric_flapping),
+  ("sozu_backend_uptime_seconds",                "gauge",   metric_uptime),
+];
+
+fn metric_active_connections(backend: &Backend, _: Instant) -> usize {
+  backend.active_connections
+}
+
+fn metric_opened_connections(backend: &Backend, _: Instant) -> usize {
+  backend.opened_connections
+}
+
+fn metric_closed_connections(backend: &Backend, _: Instant) -> usize {
+  backend.closed_connections
+}
+
+fn metric_opened_per_minute(backend: &Backend, now: Instant) -> usize {
+  backend.recent_opened_connections.count_at(now)
+}
+
+fn metric_closed_per_minute(backend: &Backend, now: Instant) -> usize {
+  backend.recent_closed_connections.count_at(now)
+}
+
+fn metric_failures(backend: &Backend, _: Instant) -> usize {
+  backend.failures
+}
+
+fn metric_available(backend: &Backend, now: Instant) -> usize {
+  if backend.can_open_at(now) { 1 } else { 0 }
+}
+
+fn metric_flapping(backend: &Backend, _: Instant) -> usize {
+  if backend.is_flapping() { 1 } else { 0 }
+}
+
+fn metric_uptime(backend: &Backend, now: Instant) -> usize {
+  if backend.can_open_at(now) {
+    backend.uptime_at(now).map(|uptime| uptime.as_secs() as usize).unwrap_or(0)
+  } else {
+    0
+  }
+}
+
+/// configuration change buffered while an application is in a transaction
 #[derive(Debug)]
-pub struct BackendMap {
-  pub backends:     HashMap<AppId, BackendList>,
+pub enum BackendChange {
+  Add(Backend),
+  Remove(SocketAddr),
+  Drain(SocketAddr),
+  Upsert(Backend),
+  /// new backends, and whether the old ones are drained
+  Swap(Vec<proxy::Backend>, bool),
+  Weight(SocketAddr, u8),
+}
+
+/// where to look for a backend when none of the app's own backends can be used
+#[derive(Debug,Clone)]
+pub enum FallbackTarget {
+  App(AppId),
+  Maintenance(Rc<RefCell<Backend>>),
+}
+
+/// how the last backend was chosen, to be attached to a trace
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Selection {
+  pub app_id:     AppId,
+  pub address:    SocketAddr,
+  pub backend_id: String,
+  pub policy:     &'static str,
+  pub sticky:     bool,
+  pub retries:    usize,
+}
+
+/// how the backend of a request was found, with the name of the load
+/// balancing policy used when it was not the sticky session's backend
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SelectionKind {
+  StickyHit,
+  StickyFallback(&'static str),
+  Fresh(&'static str),
+}
+
+/// entry of the per app audit trail of recent backend choices
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RecentSelection {
+  pub at:      Instant,
+  pub address: SocketAddr,
+  pub sticky:  bool,
+}
+
+/// what to do once an app and its fallbacks have no backend available
+#[derive(Debug,Clone)]
+pub enum OverflowPolicy {
+  FailFast,
+  /// ask the caller to retry after `max_wait`
+  Queue { max_wait: Duration },
+  Maintenance(Rc<RefCell<Backend>>),
+}
+
+impl Default for OverflowPolicy {
+  fn default() -> Self {
+    OverflowPolicy::FailFast
+  }
+}
+
+/// carried by the caller across the retries of a single request
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct RetryToken {
+  pub retries: usize,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum HealthCheckKind {
+  Tcp,
+  Http { path: String, expected_status: u16 },
+}
+
+/// how the health checker probes the backends of an app
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct HealthCheckConfig {
+  pub kind:                HealthCheckKind,
+  pub interval:            Duration,
+  pub timeout:             Duration,
+  /// consecutive failed probes before a backend is marked unhealthy
+  pub unhealthy_threshold: usize,
+  /// consecutive successful probes before a backend is marked healthy
+  pub healthy_threshold:   usize,
+}
+
+/// a backend is flapping when its health changed more than `max_transitions`
+/// times during `window`. It is then kept down for `dampening`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct FlapDetection {
+  pub window:          Duration,
+  pub max_transitions: usize,
+  pub dampening:       Duration,
+}
+
+/// disables a backend for `cooldown` after `max_failures` consecutive failed connections
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CircuitBreaker {
   pub max_failures: usize,
-  pub available:    bool,
+  pub cooldown:     Duration,
+}
+
+/// sent to the map's observer when an app runs out of available backends,
+/// then when it gets one again
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AvailabilityEvent {
+  Lost,
+  Recovered,
+}
+
+pub struct AvailabilityObserver(Box<FnMut(&str, AvailabilityEvent)>);
+
+impl fmt::Debug for AvailabilityObserver {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "AvailabilityObserver")
+  }
+}
+
+/// source of the current time for the map's decisions, the tests replace it
+/// to move past cooldowns without waiting
+pub struct Clock(Box<Fn() -> Instant>);
+
+impl fmt::Debug for Clock {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Clock")
+  }
+}
+
+#[derive(Debug)]
+pub struct BackendMap {
+  pub backends:                HashMap<AppId, BackendList>,
+  pub max_failures:            usize,
+  pub available:               bool,
+  /// map wide limit on connections that were opened but not resolved yet
+  pub max_connection_attempts: Option<usize>,
+  pub connection_attempts:     usize,
+  pub transactions:            HashMap<AppId, Vec<BackendChange>>,
+  /// used by the applications that did not choose a load balancing policy
+  pub default_load_balancing:  LoadBalancingAlgorithms,
+  pub circuit_breaker:         Option<CircuitBreaker>,
+  pub last_selection:          Option<Selection>,
+  pub observer:                Option<AvailabilityObserver>,
+  /// apps for which the observer was told that no backend is available
+  pub unavailable_apps:        HashSet<AppId>,
+  /// uses Instant::now when not set
+  pub clock:                   Option<Clock>,
+  /// one shot override of the load balancing policy, for fault injection
+  #[cfg(any(test, feature = "fault-injection"))]
+  pub forced_selection:        Option<SocketAddr>,
 }
 
 impl BackendMap {
   pub fn new() -> BackendMap {
     BackendMap {
-      backends:     HashMap::new(),
-      max_failures: 3,
-      available:    true,
+      backends:                HashMap::new(),
+      max_failures:            3,
+      available:               true,
+      max_connection_attempts: None,
+      connection_attempts:     0,
+      transactions:            HashMap::new(),
+      default_load_balancing:  LoadBalancingAlgorithms::Random,
+      circuit_breaker:         None,
+      last_selection:          None,
+      observer:                None,
+      unavailable_apps:        HashSet::new(),
+      clock:                   None,
+      #[cfg(any(test, feature = "fault-injection"))]
+      forced_selection:        None,
+    }
+  }
+
+  pub fn with_policy(policy: LoadBalancingAlgorithms) -> BackendMap {
+    BackendMap {
+      default_load_balancing: policy,
+      ..BackendMap::new()
+    }
+  }
+
+  /// the observer is called once when an app loses all its backends, and
+  /// once when a connection to one of its backends succeeds again
+  pub fn with_observer<F>(observer: F) -> BackendMap
+    where F: FnMut(&str, AvailabilityEvent) + 'static {
+    BackendMap {
+      observer: Some(AvailabilityObserver(Box::new(observer))),
+      ..BackendMap::new()
+    }
+  }
+
+  pub fn with_clock<F>(clock: F) -> BackendMap
+    where F: Fn() -> Instant + 'static {
+    BackendMap {
+      clock: Some(Clock(Box::new(clock))),
+      ..BackendMap::new()
+    }
+  }
+
+  fn now(&self) -> Instant {
+    match self.clock {
+      Some(Clock(ref clock)) => clock(),
+      None                   => Instant::now(),
+    }
+  }
+
+  fn set_app_availability(&mut self, app_id: &str, available: bool) {
+    let changed = if available {
+      self.unavailable_apps.remove(app_id)
+    } else {
+      self.unavailable_apps.insert(app_id.to_string())
+    };
+
+    if changed {
+      if let Some(AvailabilityObserver(ref mut observer)) = self.observer {
+        observer(app_id, if available { AvailabilityEvent::Recovered } else { AvailabilityEvent::Lost });
+      }
     }
   }
 
   pub fn import_configuration_state(&mut self, backends: &HashMap<AppId, Vec<proxy::Backend>>) {
+    let policy = self.default_load_balancing;
     self.backends.extend(backends.iter().map(|(ref app_id, ref backend_vec)| {
-      (app_id.to_string(), BackendList::import_configuration_state(backend_vec))
+      let mut list = BackendList::import_configuration_state(backend_vec);
+      list.set_load_balancing_policy(policy);
+      (app_id.to_string(), list)
     }));
   }
 
-  pub fn add_backend(&mut self, app_id: &str, backend: Backend) {
-    self.backends.entry(app_id.to_string()).or_insert_with(BackendList::new).add_backend(backend);
+  /// replaces all the backends of the app at once, its settings are kept
+  pub fn swap_backends(&mut self, app_id: &str, new_backends: &[proxy::Backend], drain: bool) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Swap(new_backends.to_vec(), drain));
+      return;
+    }
+
+    self.get_or_create_backend_list_for_app(app_id).swap_backends(new_backends, drain);
+  }
+
+  pub fn add_backend(&mut self, app_id: &str, backend: Backend) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Add(backend));
+      return;
+    }
+
+    self.get_or_create_backend_list_for_app(app_id).add_backend(backend);
+  }
+
+  /// adds the backend, or moves the backend with the same backend id to the new address
+  pub fn upsert_backend(&mut self, app_id: &str, backend: Backend) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Upsert(backend));
+      return;
+    }
+
+    self.get_or_create_backend_list_for_app(app_id).upsert_backend(backend);
+  }
+
+  pub fn remove_backend(&mut self, app_id: &str, backend_address: &SocketAddr) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Remove(*backend_address));
+      return;
+    }
+
+    if let Some(backends) = self.backends.get_mut(app_id) {
+      backends.remove_backend(backend_address);
+    } else {
+      error!("Backend was already removed: app id {}, address {:?}", app_id, backend_address);
+    }
+  }
+
+  /// lets the backend's connections finish before removing it
+  pub fn remove_backend_graceful(&mut self, app_id: &str, backend_address: &SocketAddr) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Drain(*backend_address));
+      return;
+    }
+
+    if let Some(backends) = self.backends.get_mut(app_id) {
+      backends.remove_backend_graceful(backend_address);
+    } else {
+      error!("Backend was already removed: app id {}, address {:?}", app_id, backend_address);
+    }
+  }
+
+  /// a backend weighted to zero gets no new traffic: it is drained, and removed
+  /// once its sticky sessions and other connections are done
+  pub fn set_backend_weight(&mut self, app_id: &str, backend_address: &SocketAddr, weight: u8) {
+    if let Some(changes) = self.transactions.get_mut(app_id) {
+      changes.push(BackendChange::Weight(*backend_address, weight));
+      return;
+    }
+
+    let found = self.backends.get_mut(app_id)
+      .map(|app_backends| app_backends.set_backend_weight(backend_address, weight))
+      .unwrap_or(false);
+    if !found {
+      error!("cannot set the weight of unknown backend: app id {}, address {:?}", app_id, backend_address);
+    }
+  }
+
+  /// until the transaction is committed, backend changes for this
+  /// application are buffered, and selection uses the previous backends
+  /// unless the application rejects it during reconfiguration
+  pub fn begin_transaction(&mut self, app_id: &str) {
+    self.transactions.entry(app_id.to_string()).or_insert_with(Vec::new);
+  }
+
+  pub fn commit_transaction(&mut self, app_id: &str) {
+    if let Some(changes) = self.transactions.remove(app_id) {
+      let app_backends = self.get_or_create_backend_list_for_app(app_id);
+      for change in changes {
+        match change {
+          BackendChange::Add(backend)    => app_backends.add_backend(backend),
+          BackendChange::Remove(address) => app_backends.remove_backend(&address),
+          BackendChange::Drain(address)  => app_backends.remove_backend_graceful(&address),
+          BackendChange::Upsert(backend) => app_backends.upsert_backend(backend),
+          BackendChange::Swap(backends, drain) => app_backends.swap_backends(&backends, drain),
+          BackendChange::Weight(address, weight) => {
+            if !app_backends.set_backend_weight(&address, weight) {
+              error!("cannot set the weight of unknown backend: app id {}, address {:?}", app_id, address);
+            }
+          },
+        }
+      }
+    }
+  }
+
+  /// changes are only applied on commit, so an app that does not reject
+  /// selection keeps using its previous backends during a transaction
+  fn check_transaction(&self, app_id: &str) -> Result<(), ConnectionError> {
+    let reject = self.backends.get(app_id).map(|app_backends| app_backends.reject_during_reconfiguration).unwrap_or(false);
+    if reject && self.transactions.contains_key(app_id) {
+      debug!("app {} is being reconfigured", app_id);
+      Err(ConnectionError::Reconfiguring)
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn report_health(&mut self, app_id: &str, backend_address: &SocketAddr, healthy: bool) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      if app_backends.has_backend(backend_address) {
+        app_backends.set_health(backend_address, healthy, Instant::now());
+        return;
+      }
+    }
+
+    error!("cannot report health of unknown backend: app id {}, address {:?}", app_id, backend_address);
+  }
+
+  /// records the result of a health check probe. The backend's health changes
+  /// once the app's threshold of consecutive identical results is reached
+  pub fn report_health_check(&mut self, app_id: &str, backend_address: &SocketAddr, success: bool) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      app_backends.record_health_check(backend_address, success);
+    } else {
+      error!("cannot report health check of unknown app: app id {}, address {:?}", app_id, backend_address);
+    }
+  }
+
+  /// number of backends of the app that can accept a new connection
+  pub fn available_count(&self, app_id: &str) -> usize {
+    let now = self.now();
+    self.backends.get(app_id).map(|app_backends| {
+      app_backends.backends.iter().filter(|backend| backend.try_borrow().map(|b| b.can_open_at(now)).unwrap_or(false)).count()
+    }).unwrap_or(0)
+  }
+
+  pub fn total_count(&self, app_id: &str) -> usize {
+    self.backends.get(app_id).map(|app_backends| app_backends.backends.len()).unwrap_or(0)
+  }
+
+  pub fn backend_by_backend_id(&mut self, app_id: &str, backend_id: &str) -> Option<Rc<RefCell<Backend>>> {
+    self.backends.get_mut(app_id)
+      .and_then(|app_backends| app_backends.find_by_backend_id(backend_id))
+      .map(|backend| backend.clone())
+  }
+
+  pub fn report_connect_time(&mut self, app_id: &str, backend_address: &SocketAddr, duration: Duration) {
+    if let Some(backend) = self.backends.get_mut(app_id).and_then(|backends| backends.find_backend(backend_address)) {
+      (*backend.borrow_mut()).record_connect_time(duration);
+    }
+  }
+
+  pub fn report_request_time(&mut self, app_id: &str, backend_address: &SocketAddr, duration: Duration) {
+    if let Some(backend) = self.backends.get_mut(app_id).and_then(|backends| backends.find_backend(backend_address)) {
+      (*backend.borrow_mut()).record_request_time(duration);
+    }
+  }
+
+  pub fn close_backend_connection(&mut self, app_id: &str, addr: &SocketAddr) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      let closed = app_backends.find_backend(addr)
+        .map(|backend| (*backend.borrow_mut()).dec_connections().is_none())
+        .unwrap_or(false);
+
+      if closed {
+        app_backends.remove_closed_backends();
+      }
+    }
+  }
+
+  /// to be called periodically with the number of live connections to the
+  /// backend, to correct a counter that drifted because a close was missed
+  pub fn reconcile_connections(&mut self, app_id: &str, backend_address: &SocketAddr, actual: usize) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      if let Some(backend) = app_backends.find_backend(backend_address) {
+        let mut backend = backend.borrow_mut();
+        let counted = backend.active_connections;
+        let drift = if counted > actual { counted - actual } else { actual - counted };
+        if drift > CONNECTION_DRIFT_THRESHOLD {
+          warn!("connection counter of backend {} at {:?} drifted: {} counted, {} actual",
+            backend.backend_id, backend_address, counted, actual);
+        } else if drift > 0 {
+          debug!("correcting connection counter of backend {} at {:?}: {} counted, {} actual",
+            backend.backend_id, backend_address, counted, actual);
+        }
+
+        backend.reconcile_connections(actual);
+      } else {
+        error!("cannot reconcile connections of unknown backend: app id {}, address {:?}", app_id, backend_address);
+        return;
+      }
+
+      app_backends.remove_closed_backends();
+    }
+  }
+
+  pub fn has_backend(&self, app_id: &str, backend: &Backend) -> bool {
+    self.backends.get(app_id).map(|backends| {
+      backends.has_backend(&backend.address)
+    }).unwrap_or(false)
+  }
+
+  pub fn set_circuit_breaker(&mut self, circuit_breaker: Option<CircuitBreaker>) {
+    self.circuit_breaker = circuit_breaker;
+  }
+
+  /// to be called on every path that failed to connect to a backend
+  fn trip_circuit_breaker(&self, backend: &mut Backend) {
+    if let Some(circuit_breaker) = self.circuit_breaker {
+      backend.trip_circuit_breaker(circuit_breaker.max_failures, circuit_breaker.cooldown, self.now());
+    }
+  }
+
+  /// attempts are only counted while a limit is set, so the count
+  /// starts again from zero once the limit is removed
+  pub fn set_max_connection_attempts(&mut self, max: Option<usize>) {
+    self.max_connection_attempts = max;
+    if max.is_none() {
+      self.connection_attempts = 0;
+    }
+  }
+
+  fn start_connection_attempt(&mut self) {
+    if self.max_connection_attempts.is_some() {
+      self.connection_attempts += 1;
+    }
+  }
+
+  /// to be called once a connection returned by this map succeeded or failed.
+  /// `poll_backend_connection` calls it for the confirmed connections, the
+  /// sessions call it when they close a connection that was still connecting
+  pub fn connection_attempt_resolved(&mut self) {
+    if self.connection_attempts > 0 {
+      self.connection_attempts -= 1;
+    }
+  }
+
+  /// checks the progress of a connection that a session opened to one of
+  /// the backends, once its socket got an event
+  pub fn poll_backend_connection(&mut self, backend: &Rc<RefCell<Backend>>, socket: &TcpStream) -> Result<BackendConnectionStatus, ConnectionError> {
+    let ref mut backend = *backend.borrow_mut();
+    let already_unavailable = backend.retry_policy.is_down();
+
+    let res = backend.poll_connect(socket);
+    match res {
+      Ok(BackendConnectionStatus::Connected) => self.connection_attempt_resolved(),
+      Err(_) => self.report_connection_failure(backend, already_unavailable),
+      Ok(_) => {},
+    }
+    res
+  }
+
+  /// for the connections that failed without an error on the socket, like
+  /// a hang up while still connecting
+  pub fn backend_connection_failed(&mut self, backend: &Rc<RefCell<Backend>>) {
+    let ref mut backend = *backend.borrow_mut();
+    let already_unavailable = backend.retry_policy.is_down();
+
+    backend.connection_failed();
+    self.report_connection_failure(backend, already_unavailable);
+  }
+
+  fn report_connection_failure(&self, backend: &mut Backend, already_unavailable: bool) {
+    incr!("backend.connections.error");
+    self.trip_circuit_breaker(backend);
+    if !already_unavailable && backend.retry_policy.is_down() {
+      error!("backend server {} at {} is down", backend.backend_id, backend.address);
+      incr!("backend.down");
+
+      push_event(proxy::ProxyEvent::BackendDown(backend.backend_id.clone(), backend.address));
+    }
+  }
+
+  fn check_connection_attempts(&self) -> Result<(), ConnectionError> {
+    match self.max_connection_attempts {
+      Some(max) if self.connection_attempts >= max => {
+        debug!("too many connection attempts in flight ({} >= {})", self.connection_attempts, max);
+        Err(ConnectionError::TooManyConnections)
+      },
+      _ => Ok(()),
+    }
+  }
+
+  pub fn backend_from_app_id(&mut self, app_id: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.connect_to_chain(app_id, None, &mut HashSet::new())
+  }
+
+  /// like backend_from_app_id, but stops trying backends once the deadline is reached
+  pub fn backend_from_app_id_with_deadline(&mut self, app_id: &str, deadline: Instant) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.connect_to_chain(app_id, Some(deadline), &mut HashSet::new())
+  }
+
+  /// connects to the app, then walks its fallback targets in order if
+  /// none of its backends are available
+  fn connect_to_chain(&mut self, app_id: &str, deadline: Option<Instant>, visited: &mut HashSet<AppId>) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    if !visited.insert(app_id.to_string()) {
+      warn!("app {} appears twice in a fallback chain, skipping it", app_id);
+      return Err(ConnectionError::NoBackendAvailable);
+    }
+
+    match self.connect_to_app(app_id, deadline) {
+      Err(ConnectionError::NoBackendAvailable) => {},
+      res => return res,
+    }
+
+    let fallbacks = self.backends.get(app_id).map(|app_backends| app_backends.fallbacks.clone()).unwrap_or_default();
+    for target in fallbacks {
+      match target {
+        FallbackTarget::App(fallback_app_id) => {
+          debug!("no backend available for app {}, falling back to app {}", app_id, fallback_app_id);
+          match self.connect_to_chain(&fallback_app_id, deadline, visited) {
+            Err(ConnectionError::NoBackendAvailable) => {},
+            res => return res,
+          }
+        },
+        FallbackTarget::Maintenance(backend) => {
+          if let Some(res) = self.connect_to_maintenance(app_id, backend) {
+            return Ok(res);
+          }
+        },
+      }
+    }
+
+    let overflow = self.backends.get(app_id).map(|app_backends| app_backends.overflow.clone()).unwrap_or_default();
+    let res = match overflow {
+      OverflowPolicy::FailFast => Err(ConnectionError::NoBackendAvailable),
+      OverflowPolicy::Queue { max_wait } => {
+        debug!("no backend available for app {}, asking to retry in {:?}", app_id, max_wait);
+        return Err(ConnectionError::RetryLater(max_wait));
+      },
+      OverflowPolicy::Maintenance(backend) => {
+        self.connect_to_maintenance(app_id, backend).ok_or(ConnectionError::NoBackendAvailable)
+      },
+    };
+
+    // the grace window only applies once nothing else can serve the request
+    if res.is_err() {
+      let now = self.now();
+      if let Some(wait) = self.backends.get_mut(app_id).and_then(|app_backends| app_backends.single_backend_grace_remaining(now)) {
+        debug!("single backend for app {} is unavailable, retry in {:?}", app_id, wait);
+        return Err(ConnectionError::RetryLater(wait));
+      }
+    }
+
+    res
+  }
+
+  fn connect_to_maintenance(&mut self, app_id: &str, backend: Rc<RefCell<Backend>>) -> Option<(Rc<RefCell<Backend>>,TcpStream)> {
+    if !backend.borrow().can_open_at(self.now()) {
+      return None;
+    }
+
+    debug!("no backend available for app {}, falling back to maintenance backend {:?}", app_id, backend.borrow().address);
+    let conn = backend.borrow_mut().try_connect();
+    if conn.is_err() {
+      self.trip_circuit_breaker(&mut backend.borrow_mut());
+    }
+    conn.ok().map(|conn| {
+      self.start_connection_attempt();
+      (backend, conn)
+    })
+  }
+
+  /// the next connection will go to this backend, whatever the app's policy
+  #[cfg(any(test, feature = "fault-injection"))]
+  pub fn force_next_selection(&mut self, address: SocketAddr) {
+    self.forced_selection = Some(address);
+  }
+
+  #[cfg(any(test, feature = "fault-injection"))]
+  fn connect_to_forced(&mut self, app_id: &str, address: SocketAddr) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    let b = self.backends.get_mut(app_id)
+      .and_then(|app_backends| app_backends.find_backend(&address))
+      .map(|backend| backend.clone())
+      .ok_or(ConnectionError::NoBackendAvailable)?;
+
+    debug!("forced selection of {:?} for app {}", address, app_id);
+    let conn = b.borrow_mut().try_connect();
+    if conn.is_err() {
+      self.trip_circuit_breaker(&mut b.borrow_mut());
+    }
+    let conn = conn?;
+    self.start_connection_attempt();
+    self.record_selection(app_id, &b.borrow(), false, 0);
+    Ok((b, conn))
+  }
+
+  fn connect_to_app(&mut self, app_id: &str, deadline: Option<Instant>) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.check_connection_attempts()?;
+    self.check_transaction(app_id)?;
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    {
+      if let Some(address) = self.forced_selection.take() {
+        return self.connect_to_forced(app_id, address);
+      }
+    }
+
+    // each attempt goes to a backend that did not fail yet for this request
+    let mut failed: Vec<SocketAddr> = Vec::new();
+    for _ in 0..self.max_failures {
+      let now = self.now();
+      if deadline.map(|d| now >= d).unwrap_or(false) {
+        debug!("deadline exceeded before connecting to a backend for app {}", app_id);
+        return Err(ConnectionError::DeadlineExceeded);
+      }
+
+      let next_backend = match self.backends.get_mut(app_id) {
+        None => return Err(ConnectionError::NoBackendAvailable),
+        Some(ref app_backends) if app_backends.backends.is_empty() => None,
+        Some(app_backends) => Some(app_backends.next_available_backend_matching(now, |backend| !failed.contains(&backend.address))),
+      };
+
+      let b = match next_backend {
+        None => {
+          self.available = false;
+          self.set_app_availability(app_id, false);
+          return Err(ConnectionError::NoBackendAvailable);
+        },
+        Some(None) => break,
+        Some(Some(b)) => b,
+      };
+
+      let res = {
+        let ref mut backend = *b.borrow_mut();
+
+        debug!("Connecting {} -> {:?}", app_id, (backend.address, backend.active_connections, backend.failures));
+        let res = backend.try_connect();
+        if res.is_err() {
+          error!("could not connect {} to {:?} ({} failures)", app_id, backend.address, backend.failures);
+          failed.push(backend.address);
+          self.trip_circuit_breaker(backend);
+        }
+        res
+      };
+
+      if let Ok(conn) = res {
+        self.available = true;
+        self.start_connection_attempt();
+        self.set_app_availability(app_id, true);
+        self.record_selection(app_id, &b.borrow(), false, failed.len());
+        return Ok((b, conn));
+      }
+    }
+
+    if !failed.is_empty() && failed.len() == self.max_failures {
+      error!("could not connect to app {} after {} attempts", app_id, failed.len());
+    }
+
+    if self.available {
+      error!("no more available backends for app {}", app_id);
+      self.available = false;
+
+      push_event(proxy::ProxyEvent::NoAvailableBackends(app_id.to_string()));
+    }
+    self.set_app_availability(app_id, false);
+    Err(ConnectionError::NoBackendAvailable)
+  }
+
+  /// runs the load balancing policy without opening a connection
+  pub fn select_backend(&mut self, app_id: &str) -> Result<Rc<RefCell<Backend>>,ConnectionError> {
+    self.check_transaction(app_id)?;
+
+    let now = self.now();
+    self.backends.get_mut(app_id)
+      .and_then(|app_backends| app_backends.next_available_backend_matching(now, |_| true))
+      .ok_or(ConnectionError::NoBackendAvailable)
+  }
+
+  /// connects to another backend than the one that just failed, as long
+  /// as the request has not used up the application's retry budget
+  pub fn retry_backend(&mut self, app_id: &str, failed_address: &SocketAddr, token: &mut RetryToken) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.check_connection_attempts()?;
+    self.check_transaction(app_id)?;
+
+    let b = {
+      let app_backends = self.backends.get_mut(app_id).ok_or(ConnectionError::NoBackendAvailable)?;
+      if token.retries >= app_backends.retry_budget {
+        debug!("retry budget of {} exhausted for app {}", app_backends.retry_budget, app_id);
+        return Err(ConnectionError::TooManyRetries);
+      }
+
+      token.retries += 1;
+      app_backends.next_available_backend_except(failed_address).ok_or(ConnectionError::NoBackendAvailable)?
+    };
+
+    let conn = b.borrow_mut().try_connect();
+    match conn {
+      Ok(c) => {
+        self.start_connection_attempt();
+        self.record_selection(app_id, &b.borrow(), false, token.retries);
+        Ok((b, c))
+      },
+      Err(e) => {
+        error!("could not retry {} on {:?} ({} retries)", app_id, b.borrow().address, token.retries);
+        self.trip_circuit_breaker(&mut b.borrow_mut());
+        Err(e)
+      }
+    }
+  }
+
+  pub fn backend_from_sticky_session(&mut self, app_id: &str, sticky_session: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.backend_with_selection_kind(app_id, Some(sticky_session)).map(|(backend, conn, _)| (backend, conn))
+  }
+
+  /// like backend_from_sticky_session, or backend_from_app_id without a sticky
+  /// session, and also tells whether the sticky cookie should be refreshed
+  pub fn backend_with_selection_kind(&mut self, app_id: &str, sticky_session: Option<&str>) -> Result<(Rc<RefCell<Backend>>,TcpStream,SelectionKind),ConnectionError> {
+    let sticky_session = match sticky_session {
+      Some(sticky_session) => sticky_session,
+      None => {
+        let (backend, conn) = self.backend_from_app_id(app_id)?;
+        return Ok((backend, conn, SelectionKind::Fresh(self.policy_name(app_id))));
+      },
+    };
+
+    self.check_connection_attempts()?;
+    self.check_transaction(app_id)?;
+
+    let now = self.now();
+    let sticky_backend = self.backends.get_mut(app_id).and_then(|app_backends| {
+      if app_backends.is_sticky_session_invalidated(sticky_session) {
+        return None;
+      }
+
+      let backend = app_backends.find_sticky(sticky_session, now).map(|b| b.clone());
+      if backend.is_some() {
+        app_backends.record_sticky_hit(sticky_session);
+      } else {
+        app_backends.record_sticky_fallback(sticky_session);
+      }
+      backend
+    });
+
+    let sticky_conn: Option<Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError>> = sticky_backend
+      .map(|b| {
+        let ref mut backend = *b.borrow_mut();
+        let conn = backend.try_connect_sticky();
+
+        conn.map(|c| (b.clone(), c)).map_err(|e| {
+          error!("could not connect {} to {:?} using session {} ({} failures)",
+            app_id, backend.address, sticky_session, backend.failures);
+          self.trip_circuit_breaker(backend);
+          e
+        })
+      });
+
+    if let Some(res) = sticky_conn {
+      if let Ok((ref b, _)) = res {
+        self.start_connection_attempt();
+        self.record_selection(app_id, &b.borrow(), true, 0);
+      }
+      return res.map(|(b, c)| (b, c, SelectionKind::StickyHit));
+    } else {
+      debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
+      let (backend, conn) = self.backend_from_app_id(app_id)?;
+      return Ok((backend, conn, SelectionKind::StickyFallback(self.policy_name(app_id))));
+    }
+  }
+
+  fn policy_name(&self, app_id: &str) -> &'static str {
+    self.backends.get(app_id).map(|app_backends| app_backends.load_balancing.name()).unwrap_or("none")
+  }
+
+  fn record_selection(&mut self, app_id: &str, backend: &Backend, sticky: bool, retries: usize) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      app_backends.record_recent_selection(backend.address, sticky);
+    }
+
+    let policy = self.policy_name(app_id);
+    self.last_selection = Some(Selection {
+      app_id:     app_id.to_string(),
+      address:    backend.address,
+      backend_id: backend.backend_id.clone(),
+      policy,
+      sticky,
+      retries,
+    });
+  }
+
+  /// the last choices made for the app, oldest first
+  pub fn recent_selections(&self, app_id: &str) -> Vec<RecentSelection> {
+    self.backends.get(app_id)
+      .map(|app_backends| app_backends.recent_selections.iter().cloned().collect())
+      .unwrap_or_default()
+  }
+
+  /// the last selection, as OpenTelemetry style span attributes
+  pub fn selection_attributes(&self) -> Vec<(&'static str, String)> {
+    match self.last_selection {
+      None => Vec::new(),
+      Some(ref selection) => vec![
+        ("sozu.app_id",              selection.app_id.clone()),
+        ("sozu.backend.address",     selection.address.to_string()),
+        ("sozu.backend.instance_id", selection.backend_id.clone()),
+        ("sozu.lb.policy",           selection.policy.to_string()),
+        ("sozu.lb.sticky",           selection.sticky.to_string()),
+        ("sozu.lb.retries",          selection.retries.to_string()),
+      ],
+    }
+  }
+
+  /// the n backends with the most active connections, all applications included
+  pub fn hottest_backends(&self, n: usize) -> Vec<(AppId, SocketAddr, usize)> {
+    let mut backends: Vec<(AppId, SocketAddr, usize)> = self.backends.iter()
+      .flat_map(|(app_id, app_backends)| {
+        app_backends.backends.iter().filter_map(move |backend| {
+          backend.try_borrow().ok().map(|backend| (app_id.to_string(), backend.address, backend.active_connections))
+        })
+      })
+      .collect();
+
+    backends.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)).then_with(|| a.1.cmp(&b.1)));
+    backends.truncate(n);
+    backends
+  }
+
+  pub fn export_metrics<W: Write>(&self, writer: &mut W, format: MetricsFormat) -> io::Result<()> {
+    let mut app_ids: Vec<&AppId> = self.backends.keys().collect();
+    app_ids.sort();
+    let now = self.now();
+
+    match format {
+      MetricsFormat::Prometheus => {
+        for &(name, kind, value) in BACKEND_METRICS.iter() {
+          writeln!(writer, "# TYPE {} {}", name, kind)?;
+          for app_id in app_ids.iter() {
+            for backend in self.backends[*app_id].backends.iter() {
+              // a backend borrowed by a selection in progress is left out of this scrape
+              let backend = match backend.try_borrow() {
+                Ok(backend) => backend,
+                Err(_)      => continue,
+              };
+              writeln!(writer, "{}{{app_id=\"{}\",backend_id=\"{}\",address=\"{}\"}} {}",
+                name, escape_label(app_id), escape_label(&backend.backend_id), backend.address, value(&backend, now))?;
+            }
+          }
+        }
+      },
+      MetricsFormat::Json => {
+        write!(writer, "{{")?;
+        for (i, app_id) in app_ids.iter().enumerate() {
+          if i > 0 {
+            write!(writer, ",")?;
+          }
+          write!(writer, "\"{}\":[", escape_json(app_id))?;
+          let mut first = true;
+          for backend in self.backends[*app_id].backends.iter() {
+            let backend = match backend.try_borrow() {
+              Ok(backend) => backend,
+              Err(_)      => continue,
+            };
+            if !first {
+              write!(writer, ",")?;
+            }
+            first = false;
+            write!(writer, "{{\"backend_id\":\"{}\",\"address\":\"{}\"",
+              escape_json(&backend.backend_id), backend.address)?;
+            for &(name, _, value) in BACKEND_METRICS.iter() {
+              write!(writer, ",\"{}\":{}", name.trim_start_matches("sozu_backend_"), value(&backend, now))?;
+            }
+            write!(writer, "}}")?;
+          }
+          write!(writer, "]")?;
+        }
+        writeln!(writer, "}}")?;
+      },
+    }
+
+    Ok(())
+  }
+
+  pub fn set_load_balancing_policy_for_app(&mut self, app_id: &str, lb_algo: LoadBalancingAlgorithms) {
+    // The application can be created before the backends were registered because of the async config messages.
+    // So when we set the load balancing policy, we have to create the backend list if if it doesn't exist yet.
+    let app_backends = self.get_or_create_backend_list_for_app(app_id);
+    app_backends.set_load_balancing_policy(lb_algo);
+  }
+
+  /// replaces the app's load balancing with a custom implementation,
+  /// for strategies that are not covered by `LoadBalancingAlgorithms`
+  pub fn set_load_balancing_algorithm_for_app(&mut self, app_id: &str, load_balancing: Box<LoadBalancingAlgorithm>) {
+    self.get_or_create_backend_list_for_app(app_id).set_load_balancing(load_balancing);
+  }
+
+  /// only the weighted random policy uses weights, the call is ignored
+  /// for the other applications
+  pub fn rebalance_weights(&mut self, app_id: &str) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      if app_backends.load_balancing.name() != "weightedrandom" {
+        warn!("not rebalancing the weights of app {}, its {} policy does not use them",
+          app_id, app_backends.load_balancing.name());
+        return;
+      }
+
+      app_backends.rebalance_weights();
+    }
+  }
+
+  /// goes back to the configured weights after a rebalance
+  pub fn reset_weights(&mut self, app_id: &str) {
+    if let Some(app_backends) = self.backends.get_mut(app_id) {
+      for backend in app_backends.backends.iter() {
+        backend.borrow_mut().rebalanced_weight = None;
+      }
+    }
+  }
+
+  pub fn set_single_backend_grace_for_app(&mut self, app_id: &str, grace: Option<Duration>) {
+    self.get_or_create_backend_list_for_app(app_id).single_backend_grace = grace;
+  }
+
+  pub fn set_max_sticky_fallbacks_for_app(&mut self, app_id: &str, max_sticky_fallbacks: Option<usize>) {
+    self.get_or_create_backend_list_for_app(app_id).max_sticky_fallbacks = max_sticky_fallbacks;
+  }
+
+  pub fn set_sticky_draining_grace_for_app(&mut self, app_id: &str, grace: bool) {
+    self.get_or_create_backend_list_for_app(app_id).sticky_draining_grace = grace;
+  }
+
+  pub fn set_reject_during_reconfiguration_for_app(&mut self, app_id: &str, reject: bool) {
+    self.get_or_create_backend_list_for_app(app_id).reject_during_reconfiguration = reject;
+  }
+
+  /// when true, the client's sticky cookie points to a backend that kept failing
+  /// and should be replaced
+  pub fn is_sticky_session_invalidated(&self, app_id: &str, sticky_session: &str) -> bool {
+    self.backends.get(app_id)
+      .map(|app_backends| app_backends.is_sticky_session_invalidated(sticky_session))
+      .unwrap_or(false)
+  }
+
+  pub fn set_health_check_for_app(&mut self, app_id: &str, health_check: Option<HealthCheckConfig>) {
+    self.get_or_create_backend_list_for_app(app_id).health_check = health_check;
+  }
+
+  pub fn health_check_for_app(&self, app_id: &str) -> Option<&HealthCheckConfig> {
+    self.backends.get(app_id).and_then(|app_backends| app_backends.health_check.as_ref())
+  }
+
+  pub fn set_flap_detection_for_app(&mut self, app_id: &str, flap_detection: Option<FlapDetection>) {
+    self.get_or_create_backend_list_for_app(app_id).flap_detection = flap_detection;
+  }
+
+  pub fn set_fallbacks_for_app(&mut self, app_id: &str, fallbacks: Vec<FallbackTarget>) {
+    self.get_or_create_backend_list_for_app(app_id).fallbacks = fallbacks;
+  }
+
+  pub fn set_overflow_policy_for_app(&mut self, app_id: &str, overflow: OverflowPolicy) {
+    self.get_or_create_backend_list_for_app(app_id).overflow = overflow;
+  }
+
+  pub fn set_retry_budget_for_app(&mut self, app_id: &str, retry_budget: usize) {
+    self.get_or_create_backend_list_for_app(app_id).retry_budget = retry_budget;
+  }
+
+  pub fn sticky_cookie_name_for_app(&self, app_id: &str) -> String {
+    self.backends.get(app_id)
+      .map(|app_backends| app_backends.sticky_cookie_name())
+      .unwrap_or_else(proxy::default_sticky_name)
+  }
+
+  pub fn set_sticky_cookie_name_for_app(&mut self, app_id: &str, sticky_cookie_name: Option<String>) {
+    self.get_or_create_backend_list_for_app(app_id).set_sticky_cookie_name(sticky_cookie_name);
+  }
+
+  pub fn get_or_create_backend_list_for_app(&mut self, app_id: &str) -> &mut BackendList {
+    let policy = self.default_load_balancing;
+    self.backends.entry(app_id.to_string()).or_insert_with(|| BackendList::with_policy(policy))
+  }
+}
+
+const MAX_REBALANCED_WEIGHT: usize = 100;
+const DEFAULT_RETRY_BUDGET: usize = 2;
+const RECENT_SELECTIONS_CAPACITY: usize = 64;
+/// difference between the connection counter and the actual connections
+/// beyond which a leak is reported
+const CONNECTION_DRIFT_THRESHOLD: usize = 10;
+
+#[derive(Debug)]
+pub struct BackendList {
+  pub backends:                Vec<Rc<RefCell<Backend>>>,
+  pub next_id:                 u32,
+  pub load_balancing:          Box<LoadBalancingAlgorithm>,
+  /// when the app has a single backend, how long to ask for a retry
+  /// instead of failing while that backend is down
+  pub single_backend_grace:    Option<Duration>,
+  pub unavailable_since:       Option<Instant>,
+  pub sticky_cookie_name:      Option<String>,
+  /// consecutive fallbacks after which a sticky session is invalidated
+  pub max_sticky_fallbacks:    Option<usize>,
+  pub sticky_fallbacks:        HashMap<String, usize>,
+  pub invalid_sticky_sessions: HashSet<String>,
+  /// how many times a single request may be retried on another backend
+  pub retry_budget:            usize,
+  /// keep routing sticky sessions to draining backends
+  pub sticky_draining_grace:   bool,
+  pub health_check:            Option<HealthCheckConfig>,
+  /// last probe result for each backend, and how many times in a row it happened
+  pub health_check_streaks:    HashMap<SocketAddr, (bool, usize)>,
+  pub flap_detection:          Option<FlapDetection>,
+  pub health_transitions:      HashMap<SocketAddr, Vec<Instant>>,
+  pub fallbacks:               Vec<FallbackTarget>,
+  pub overflow:                OverflowPolicy,
+  /// bounded to RECENT_SELECTIONS_CAPACITY, the oldest entries are dropped first
+  pub recent_selections:       VecDeque<RecentSelection>,
+  /// return ConnectionError::Reconfiguring during a transaction
+  /// instead of serving the backends from before it
+  pub reject_during_reconfiguration: bool,
+}
+
+impl BackendList {
+  pub fn new() -> BackendList {
+    BackendList {
+      backends:                Vec::new(),
+      next_id:                 0,
+      load_balancing:          Box::new(RandomAlgorithm::new()),
+      single_backend_grace:    None,
+      unavailable_since:       None,
+      sticky_cookie_name:      None,
+      max_sticky_fallbacks:    None,
+      sticky_fallbacks:        HashMap::new(),
+      invalid_sticky_sessions: HashSet::new(),
+      retry_budget:            DEFAULT_RETRY_BUDGET,
+      sticky_draining_grace:   true,
+      health_check:            None,
+      health_check_streaks:    HashMap::new(),
+      flap_detection:          None,
+      health_transitions:      HashMap::new(),
+      fallbacks:               Vec::new(),
+      overflow:                OverflowPolicy::FailFast,
+      recent_selections:       VecDeque::with_capacity(RECENT_SELECTIONS_CAPACITY),
+      reject_during_reconfiguration: false,
+    }
+  }
+
+  pub fn with_policy(policy: LoadBalancingAlgorithms) -> BackendList {
+    let mut list = BackendList::new();
+    list.set_load_balancing_policy(policy);
+    list
+  }
+
+  pub fn record_recent_selection(&mut self, address: SocketAddr, sticky: bool) {
+    if self.recent_selections.len() == RECENT_SELECTIONS_CAPACITY {
+      self.recent_selections.pop_front();
+    }
+
+    self.recent_selections.push_back(RecentSelection { at: Instant::now(), address, sticky });
+  }
+
+  pub fn import_configuration_state(backend_vec: &[proxy::Backend]) -> BackendList {
+    let mut list = BackendList::new();
+    for ref backend in backend_vec {
+      list.add_backend(backend_from_configuration(backend));
+    }
+
+    list
+  }
+
+  /// replaces the backends but keeps the application's settings. The state
+  /// tracked for addresses that are not used anymore is dropped
+  pub fn swap_backends(&mut self, new_backends: &[proxy::Backend], drain: bool) {
+    let old_backends = ::std::mem::replace(&mut self.backends, Vec::new());
+    if drain {
+      for backend in old_backends.iter() {
+        (*backend.borrow_mut()).set_closing();
+      }
+    }
+
+    for backend in new_backends {
+      self.add_backend(backend_from_configuration(backend));
+    }
+
+    let addresses: HashSet<SocketAddr> = self.backends.iter().map(|backend| backend.borrow().address).collect();
+    self.health_check_streaks.retain(|address, _| addresses.contains(address));
+    self.health_transitions.retain(|address, _| addresses.contains(address));
+    self.prune_sticky_sessions();
+  }
+
+  pub fn add_backend(&mut self, backend: Backend) {
+    // a draining backend can share its id with the one replacing it
+    if self.backends.iter().any(|b| b.borrow().status == BackendStatus::Normal && b.borrow().backend_id == backend.backend_id) {
+      error!("cannot add backend {} at {}, another backend already uses this id", backend.backend_id, backend.address);
+      return;
+    }
+
+    if self.backends.iter().find(|b| (*b.borrow()).address == backend.address).is_none() {
+      if let Some(ref sticky_id) = backend.sticky_id {
+        self.invalid_sticky_sessions.remove(sticky_id);
+
+        if self.backends.iter().any(|b| b.borrow().sticky_id.as_ref() == Some(sticky_id)) {
+          warn!("backend {} at {} reuses the sticky id {} of another backend",
+            backend.backend_id, backend.address, sticky_id);
+        }
+      }
+
+      let backend = Rc::new(RefCell::new(backend));
+      self.backends.push(backend);
+      self.next_id += 1;
+    }
+
+    self.check_invariants("add_backend");
+  }
+
+  /// backends are identified by their backend id here: when an instance is
+  /// rescheduled to another address, the existing backend is moved there
+  /// and its failures are forgotten
+  pub fn upsert_backend(&mut self, backend: Backend) {
+    let existing = self.backends.iter().position(|b| {
+      let b = b.borrow();
+      b.status == BackendStatus::Normal && b.backend_id == backend.backend_id
+    });
+
+    match existing {
+      None => self.add_backend(backend),
+      Some(index) if self.backends[index].borrow().address == backend.address => {},
+      Some(index) => {
+        if self.has_backend(&backend.address) {
+          // another backend already serves the new address, the stale one
+          // gets no new traffic and is removed once its connections are done
+          self.backends[index].borrow_mut().set_closing();
+          self.remove_closed_backends();
+        } else {
+          let mut existing = self.backends[index].borrow_mut();
+          info!("backend {} moved from {} to {}", existing.backend_id, existing.address, backend.address);
+          existing.address        = backend.address;
+          existing.retry_policy   = backend.retry_policy;
+          existing.failures       = 0;
+          existing.healthy        = None;
+          existing.circuit_breaker = CircuitBreakerState::Closed;
+          existing.update_availability(Instant::now());
+        }
+      },
+    }
+
+    self.check_invariants("upsert_backend");
+  }
+
+  pub fn add_backend_weighted(&mut self, backend_id: &str, backend_address: SocketAddr, weight: u8) {
+    self.add_backend(Backend::new(backend_id, backend_address, None, Some(LoadBalancingParams { weight }), None));
+  }
+
+  pub fn remove_backend(&mut self, backend_address: &SocketAddr) {
+    self.backends.retain(|backend| &(*backend.borrow()).address != backend_address);
+    self.prune_sticky_sessions();
+
+    self.check_invariants("remove_backend");
+  }
+
+  /// stops sending new connections to the backend, it is removed
+  /// once its last connection is closed
+  /// returns false if there is no backend at this address
+  pub fn set_backend_weight(&mut self, backend_address: &SocketAddr, weight: u8) -> bool {
+    match self.find_backend(backend_address) {
+      Some(backend) => {
+        let mut backend = backend.borrow_mut();
+        backend.load_balancing_parameters = Some(LoadBalancingParams { weight });
+        backend.rebalanced_weight = None;
+      },
+      None => return false,
+    }
+
+    if weight == 0 {
+      self.remove_backend_graceful(backend_address);
+    }
+    true
+  }
+
+  pub fn remove_backend_graceful(&mut self, backend_address: &SocketAddr) {
+    if let Some(backend) = self.find_backend(backend_address) {
+      (*backend.borrow_mut()).set_closing();
+    }
+
+    self.remove_closed_backends();
+  }
+
+  /// drops the draining backends that have no connection left
+  pub fn remove_closed_backends(&mut self) {
+    self.backends.retain(|backend| {
+      let backend = backend.borrow();
+      match backend.status {
+        BackendStatus::Normal  => true,
+        BackendStatus::Closing => backend.active_connections > 0,
+        BackendStatus::Closed  => false,
+      }
+    });
+    self.prune_sticky_sessions();
+
+    self.check_invariants("remove_closed_backends");
+  }
+
+  pub fn has_backend(&self, backend_address: &SocketAddr) -> bool {
+    self.backends.iter().any(|backend| &(*backend.borrow()).address == backend_address)
+  }
+
+  pub fn find_backend(&mut self, backend_address: &SocketAddr) -> Option<&mut Rc<RefCell<Backend>>> {
+    self.backends.iter_mut().find(|backend| &(*backend.borrow()).address == backend_address)
+  }
+
+  /// backend ids are not unique in a list, the first matching backend is returned
+  pub fn find_by_backend_id(&mut self, backend_id: &str) -> Option<&mut Rc<RefCell<Backend>>> {
+    let matching = self.backends.iter().filter(|backend| backend.borrow().backend_id == backend_id).count();
+    if matching > 1 {
+      warn!("backend id {} matches {} backends, using the first one", backend_id, matching);
+    }
+
+    self.backends.iter_mut().find(|backend| backend.borrow().backend_id == backend_id)
+  }
+
+  pub fn record_health_check(&mut self, backend_address: &SocketAddr, success: bool) {
+    let threshold = match self.health_check {
+      Some(ref health_check) if success => health_check.healthy_threshold,
+      Some(ref health_check)            => health_check.unhealthy_threshold,
+      None => {
+        warn!("received a health check result for {:?}, but no health check is configured", backend_address);
+        return;
+      },
+    };
+
+    let count = {
+      let streak = self.health_check_streaks.entry(*backend_address).or_insert((success, 0));
+      if streak.0 != success {
+        *streak = (success, 0);
+      }
+      streak.1 += 1;
+      streak.1
+    };
+
+    if count >= threshold {
+      self.set_health(backend_address, success, Instant::now());
+    }
+  }
+
+  /// updates the backend's health, and holds it down if it changed
+  /// too often recently
+  pub fn set_health(&mut self, backend_address: &SocketAddr, healthy: bool, now: Instant) {
+    let changed = match self.find_backend(backend_address) {
+      Some(backend) => {
+        let mut backend = backend.borrow_mut();
+        let changed = backend.healthy.map(|previous| previous != healthy).unwrap_or(false);
+        backend.healthy = Some(healthy);
+        backend.update_availability(now);
+        changed
+      },
+      None => return,
+    };
+
+    let flap_detection = match self.flap_detection {
+      Some(flap_detection) if changed => flap_detection,
+      _ => return,
+    };
+
+    let flapping = {
+      let transitions = self.health_transitions.entry(*backend_address).or_insert_with(Vec::new);
+      transitions.retain(|&at| now.duration_since(at) < flap_detection.window);
+      transitions.push(now);
+      transitions.len() > flap_detection.max_transitions
+    };
+
+    if flapping {
+      warn!("backend {:?} is flapping, keeping it down for {:?}", backend_address, flap_detection.dampening);
+      self.health_transitions.remove(backend_address);
+      if let Some(backend) = self.find_backend(backend_address) {
+        let mut backend = backend.borrow_mut();
+        backend.flapping_until = Some(now + flap_detection.dampening);
+        backend.update_availability(now);
+      }
+    }
+  }
+
+  pub fn find_sticky(&mut self, sticky_session: &str, now: Instant) -> Option<&mut Rc<RefCell<Backend>>> {
+    let matching = self.backends.iter()
+      .filter(|b| b.borrow().sticky_session_id() == sticky_session)
+      .count();
+    if matching > 1 {
+      warn!("sticky session {} matches {} backends, using the first one that can open", sticky_session, matching);
+    }
+
+    let draining_grace = self.sticky_draining_grace;
+    self.backends.iter_mut()
+      .filter(|b| b.borrow().sticky_session_id() == sticky_session)
+      .find(|b| {
+        let backend = b.borrow();
+        if draining_grace { backend.can_open_sticky_at(now) } else { backend.can_open_at(now) }
+      })
+  }
+
+  /// forgets the fallbacks and invalidations of sticky sessions whose backend is gone
+  fn prune_sticky_sessions(&mut self) {
+    if self.sticky_fallbacks.is_empty() && self.invalid_sticky_sessions.is_empty() {
+      return;
+    }
+
+    let sticky_ids: HashSet<String> = self.backends.iter().map(|b| b.borrow().sticky_session_id().to_string()).collect();
+    self.sticky_fallbacks.retain(|sticky_id, _| sticky_ids.contains(sticky_id));
+    self.invalid_sticky_sessions.retain(|sticky_id| sticky_ids.contains(sticky_id));
+  }
+
+  pub fn is_sticky_session_invalidated(&self, sticky_session: &str) -> bool {
+    self.invalid_sticky_sessions.contains(sticky_session)
+  }
+
+  pub fn record_sticky_hit(&mut self, sticky_session: &str) {
+    self.sticky_fallbacks.remove(sticky_session);
+  }
+
+  /// the sticky session comes from the client's cookie, so only the sessions
+  /// of known backends are tracked: both sets stay bounded by the backend count
+  pub fn record_sticky_fallback(&mut self, sticky_session: &str) {
+    if !self.backends.iter().any(|b| b.borrow().sticky_session_id() == sticky_session) {
+      return;
+    }
+
+    if let Some(max) = self.max_sticky_fallbacks {
+      let fallbacks = {
+        let count = self.sticky_fallbacks.entry(sticky_session.to_string()).or_insert(0);
+        *count += 1;
+        *count
+      };
+
+      if fallbacks >= max {
+        info!("invalidating sticky session {} after {} consecutive fallbacks", sticky_session, fallbacks);
+        self.sticky_fallbacks.remove(sticky_session);
+        self.invalid_sticky_sessions.insert(sticky_session.to_string());
+      }
+    }
+  }
+
+  /// backends that are mutably borrowed elsewhere are not considered available
+  pub fn available_backends(&self, backup: bool, now: Instant) -> Vec<Rc<RefCell<Backend>>> {
+    self.backends.iter()
+      .filter(|backend| backend.try_borrow().map(|b| b.backup == backup && b.can_open_at(now)).unwrap_or(false))
+      .map(|backend| (*backend).clone())
+      .collect()
+  }
+
+  pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
+    self.next_available_backend_matching(Instant::now(), |_| true)
+  }
+
+  pub fn next_available_backend_except(&mut self, excluded_address: &SocketAddr) -> Option<Rc<RefCell<Backend>>> {
+    self.next_available_backend_matching(Instant::now(), |backend| &backend.address != excluded_address)
+  }
+
+  fn next_available_backend_matching<F: Fn(&Backend) -> bool>(&mut self, now: Instant, predicate: F) -> Option<Rc<RefCell<Backend>>> {
+    // the sessions release their connections on the backend itself, so a
+    // drained backend is only noticed here
+    let closed = self.backends.iter()
+      .map(|backend| backend.try_borrow().map(|b| b.status == BackendStatus::Closed))
+      .collect::<Result<Vec<bool>, _>>()
+      .map(|statuses| statuses.contains(&true))
+      .unwrap_or(false);
+    if closed {
+      self.remove_closed_backends();
+    }
+
+    let mut backends: Vec<Rc<RefCell<Backend>>> = self.available_backends(false, now).into_iter()
+      .filter(|backend| predicate(&backend.borrow()))
+      .collect();
+
+    if backends.is_empty() {
+      backends = self.available_backends(true, now).into_iter()
+        .filter(|backend| predicate(&backend.borrow()))
+        .collect();
+    }
+
+    if backends.is_empty() {
+      None
+    } else {
+      self.unavailable_since = None;
+      self.load_balancing.next_available_backend(&backends)
+    }
+  }
+
+  pub fn sticky_cookie_name(&self) -> String {
+    self.sticky_cookie_name.clone().unwrap_or_else(proxy::default_sticky_name)
+  }
+
+  pub fn set_sticky_cookie_name(&mut self, sticky_cookie_name: Option<String>) {
+    self.sticky_cookie_name = sticky_cookie_name;
+  }
+
+  pub fn single_backend_grace_remaining(&mut self, now: Instant) -> Option<Duration> {
+    let grace = self.single_backend_grace?;
+    if self.backends.len() != 1 {
+      return None;
+    }
+
+    let end = *self.unavailable_since.get_or_insert(now) + grace;
+    if now < end {
+      Some(end - now)
+    } else {
+      None
+    }
+  }
+
+  /// one shot adjustment of the weights, inversely proportional to the
+  /// current number of connections, to even out the load. The configured
+  /// weights are left untouched
+  pub fn rebalance_weights(&mut self) {
+    let least_connections = match self.backends.iter().map(|b| b.borrow().active_connections).min() {
+      Some(count) => count,
+      None        => return,
+    };
+
+    for backend in self.backends.iter() {
+      let ref mut backend = *backend.borrow_mut();
+      let weight = MAX_REBALANCED_WEIGHT * (least_connections + 1) / (backend.active_connections + 1);
+      backend.rebalanced_weight = Some(::std::cmp::max(1, weight) as u8);
+    }
+  }
+
+  #[cfg(debug_assertions)]
+  pub fn validate(&self) -> Result<(), String> {
+    let mut addresses = HashSet::new();
+    let mut backend_ids = HashSet::new();
+    let weighted = self.load_balancing.name() == "weightedrandom";
+    for backend in self.backends.iter() {
+      let backend = backend.borrow();
+      if !addresses.insert(backend.address) {
+        return Err(format!("duplicate backend address {}", backend.address));
+      }
+
+      if backend.status == BackendStatus::Normal && !backend_ids.insert(backend.backend_id.clone()) {
+        return Err(format!("duplicate backend id {}", backend.backend_id));
+      }
+
+      if !weighted && backend.rebalanced_weight.is_some() {
+        return Err(format!("backend {} has a rebalanced weight under the {} policy", backend.backend_id, self.load_balancing.name()));
+      }
+    }
+
+    Ok(())
+  }
+
+  #[cfg(debug_assertions)]
+  fn check_invariants(&self, operation: &str) {
+    if let Err(e) = self.validate() {
+      panic!("invalid backend list after {}: {}", operation, e);
+    }
+  }
+
+  #[cfg(not(debug_assertions))]
+  fn check_invariants(&self, _operation: &str) {}
+
+  /// runs a policy over the backends at the given indexes only, whatever
+  /// their availability. The algorithm is passed by the caller so that its
+  /// state, like the round robin position, carries over between selections
+  pub fn select_from(&self, candidates: &[usize], algorithm: &mut LoadBalancingAlgorithm) -> Option<Rc<RefCell<Backend>>> {
+    let backends: Vec<Rc<RefCell<Backend>>> = candidates.iter()
+      .filter_map(|index| self.backends.get(*index))
+      .cloned()
+      .collect();
+
+    if backends.is_empty() {
+      None
+    } else {
+      algorithm.next_available_backend(&backends)
+    }
+  }
+
+  pub fn set_load_balancing_policy(&mut self, load_balancing_policy: LoadBalancingAlgorithms) {
+    self.set_load_balancing(load_balancing_algorithm(load_balancing_policy));
+  }
+
+  /// the rebalanced weights are dropped for policies that do not use them
+  pub fn set_load_balancing(&mut self, load_balancing: Box<LoadBalancingAlgorithm>) {
+    self.load_balancing = load_balancing;
+    if self.load_balancing.name() != "weightedrandom" {
+      for backend in self.backends.iter() {
+        backend.borrow_mut().rebalanced_weight = None;
+      }
+    }
+
+    self.check_invariants("set_load_balancing");
+  }
+}
+
+fn load_balancing_algorithm(policy: LoadBalancingAlgorithms) -> Box<LoadBalancingAlgorithm> {
+  match policy {
+    LoadBalancingAlgorithms::RoundRobin => Box::new(RoundRobinAlgorithm{ next_backend: 0 }),
+    LoadBalancingAlgorithms::Random => Box::new(RandomAlgorithm::new()),
+    LoadBalancingAlgorithms::LeastConnections => Box::new(LeastConnectionsAlgorithm{}),
+    LoadBalancingAlgorithms::WeightedRandom => Box::new(WeightedRandomAlgorithm::new()),
+    LoadBalancingAlgorithms::ConfigOrder => Box::new(ConfigOrderAlgorithm{}),
+  }
+}
+
+fn backend_from_configuration(backend: &proxy::Backend) -> Backend {
+  Backend::new(&backend.backend_id, backend.address, backend.sticky_id.clone(), backend.load_balancing_parameters.clone(), backend.backup)
+}
+
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_json(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '"'  => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod backends_test {
+
+  use super::*;
+  use {BackendStatus,BackendConnectionStatus,RollingCounter};
+  use retry::RetryPolicy;
+  use std::{thread,sync::mpsc::*,net::TcpListener,cell::Cell};
+  use sozu_command::config::{BackendConfig,backend_weights};
+  use rand::{SeedableRng,rngs::StdRng};
+
+
+  fn run_mock_tcp_server(addr: &str, stopper: Receiver<()>) {
+    let mut run = true;
+    let listener = TcpListener::bind(addr).unwrap();
+
+    thread::spawn(move || {
+      while run {
+        for stream in listener.incoming() {
+          // accept connections
+          if let Ok(()) = stopper.try_recv() {
+            run = false;
+          }
+        }
+
+      }
+    });
+  }
+
+  #[test]
+  fn it_should_retrieve_a_backend_from_app_id_when_backends_have_been_recorded() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+
+    let backend_addr = "127.0.0.1:1236";
+    let (sender, receiver) = channel();
+    run_mock_tcp_server(backend_addr, receiver);
+
+    backend_map.add_backend(app_id, Backend::new(&format!("{}-1", app_id), backend_addr.parse().unwrap(), None, None, None));
+
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
+    sender.send(()).unwrap();
+  }
+
+  #[test]
+  fn it_should_not_retrieve_a_backend_from_app_id_when_backend_has_not_been_recorded() {
+    let mut backend_map = BackendMap::new();
+    let app_not_recorded = "not";
+    backend_map.add_backend("foo", Backend::new("foo-1", "127.0.0.1:9001".parse().unwrap(), None, None, None));
+
+    assert!(backend_map.backend_from_app_id(app_not_recorded).is_err());
+  }
+
+  #[test]
+  fn it_should_not_retrieve_a_backend_from_app_id_when_backend_list_is_empty() {
+    let mut backend_map = BackendMap::new();
+
+    assert!(backend_map.backend_from_app_id("dumb").is_err());
+  }
+
+  #[test]
+  fn it_should_retrieve_a_backend_from_sticky_session_when_the_backend_has_been_recorded() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let sticky_session = "server-2";
+
+    let backend_addr = "127.0.0.1:3456";
+    let (sender, receiver) = channel();
+    run_mock_tcp_server(backend_addr, receiver);
+
+    backend_map.add_backend(app_id, Backend::new(&format!("{}-1", app_id), "127.0.0.1:9001".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new(&format!("{}-2", app_id), "127.0.0.1:9000".parse().unwrap(), Some("server-2".to_string()), None, None));
+    // sticky backend
+    backend_map.add_backend(app_id, Backend::new(&format!("{}-3", app_id), backend_addr.parse().unwrap(), Some("server-3".to_string()), None, None));
+
+    assert!(backend_map.backend_from_sticky_session(app_id, sticky_session).is_ok());
+    sender.send(()).unwrap();
+  }
+
+  #[test]
+  fn it_should_not_retrieve_a_backend_from_sticky_session_when_the_backend_has_not_been_recorded() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let sticky_session = "test";
+
+    assert!(backend_map.backend_from_sticky_session(app_id, sticky_session).is_err());
+  }
+
+  #[test]
+  fn it_should_not_retrieve_a_backend_from_sticky_session_when_the_backend_list_is_empty() {
+    let mut backend_map = BackendMap::new();
+    let myapp_not_recorded = "myapp";
+    let sticky_session = "test";
+
+    assert!(backend_map.backend_from_sticky_session(myapp_not_recorded, sticky_session).is_err());
+  }
+
+  #[test]
+  fn it_should_add_a_backend_when_he_doesnt_already_exist() {
+    let backend_id = "myback";
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+
+    assert_eq!(1, backends_list.backends.len());
+  }
+
+  #[test]
+  fn it_should_not_add_a_backend_when_he_already_exist() {
+    let backend_id = "myback";
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+
+    //same backend id
+    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+
+    assert_eq!(1, backends_list.backends.len());
+  }
+
+  fn config_backend(app_id: &str, backend_id: &str, address: &str) -> proxy::Backend {
+    proxy::Backend {
+      app_id:                    app_id.to_string(),
+      backend_id:                backend_id.to_string(),
+      address:                   address.parse().unwrap(),
+      sticky_id:                 None,
+      load_balancing_parameters: None,
+      backup:                    None,
+    }
+  }
+
+  #[test]
+  fn it_should_only_select_new_backends_after_a_swap() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1240".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1241".parse().unwrap(), None, None, None));
+    let old_backend = backend_map.backends[app_id].backends[0].clone();
+
+    let new_backends = vec![
+      config_backend(app_id, "myapp-3", "127.0.0.1:1242"),
+      config_backend(app_id, "myapp-4", "127.0.0.1:1243"),
+    ];
+    backend_map.swap_backends(app_id, &new_backends, true);
+
+    let new_addresses: Vec<SocketAddr> = vec!["127.0.0.1:1242".parse().unwrap(), "127.0.0.1:1243".parse().unwrap()];
+    let app_backends = backend_map.backends.get_mut(app_id).unwrap();
+    assert_eq!(2, app_backends.backends.len());
+    for _ in 0..20 {
+      let backend = app_backends.next_available_backend().unwrap();
+      assert!(new_addresses.contains(&backend.borrow().address));
+    }
+
+    assert_eq!(BackendStatus::Closing, old_backend.borrow().status);
+  }
+
+  #[test]
+  fn it_should_keep_the_app_settings_across_a_swap() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1244".parse().unwrap(), None, None, None));
+    backend_map.set_sticky_cookie_name_for_app(app_id, Some("MYAPP".to_string()));
+    backend_map.set_retry_budget_for_app(app_id, 5);
+    backend_map.set_single_backend_grace_for_app(app_id, Some(Duration::from_secs(2)));
+    backend_map.set_reject_during_reconfiguration_for_app(app_id, true);
+
+    backend_map.swap_backends(app_id, &[config_backend(app_id, "myapp-2", "127.0.0.1:1245")], false);
+
+    let app_backends = &backend_map.backends[app_id];
+    assert_eq!("MYAPP", app_backends.sticky_cookie_name());
+    assert_eq!(5, app_backends.retry_budget);
+    assert_eq!(Some(Duration::from_secs(2)), app_backends.single_backend_grace);
+    assert!(app_backends.reject_during_reconfiguration);
+    assert_eq!(1, app_backends.backends.len());
+  }
+
+  #[test]
+  fn it_should_buffer_a_swap_until_the_transaction_is_committed() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let old_address: SocketAddr = "127.0.0.1:1246".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", old_address, None, None, None));
+
+    backend_map.begin_transaction(app_id);
+    backend_map.swap_backends(app_id, &[config_backend(app_id, "myapp-2", "127.0.0.1:1247")], true);
+    assert_eq!(old_address, backend_map.select_backend(app_id).unwrap().borrow().address);
+
+    backend_map.commit_transaction(app_id);
+    assert_eq!("myapp-2", backend_map.select_backend(app_id).unwrap().borrow().backend_id);
+    assert!(!backend_map.backends[app_id].has_backend(&old_address));
+  }
+
+  #[test]
+  fn it_should_exclude_backends_reported_unhealthy() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1250".parse().unwrap();
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1251".parse().unwrap(), None, None, None));
+
+    backend_map.report_health(app_id, &address, false);
+    let available = backend_map.backends.get_mut(app_id).unwrap().available_backends(false, Instant::now());
+    assert_eq!(1, available.len());
+    assert!(available.iter().all(|b| b.borrow().address != address));
+
+    backend_map.report_health(app_id, &address, true);
+    let available = backend_map.backends.get_mut(app_id).unwrap().available_backends(false, Instant::now());
+    assert_eq!(2, available.len());
+    assert!(available.iter().any(|b| b.borrow().address == address));
+  }
+
+  #[test]
+  fn it_should_export_backend_metrics_in_prometheus_format() {
+    let mut backend_map = BackendMap::new();
+    backend_map.add_backend("app_1", Backend::new("app_1-0", "127.0.0.1:1260".parse().unwrap(), None, None, None));
+    backend_map.add_backend("app_2", Backend::new("app_2-0", "127.0.0.1:1261".parse().unwrap(), None, None, None));
+    backend_map.backends["app_1"].backends[0].borrow_mut().active_connections = 3;
+
+    let mut output = Vec::new();
+    backend_map.export_metrics(&mut output, MetricsFormat::Prometheus).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines.contains(&"# TYPE sozu_backend_active_connections gauge"));
+    assert!(lines.contains(&"sozu_backend_active_connections{app_id=\"app_1\",backend_id=\"app_1-0\",address=\"127.0.0.1:1260\"} 3"));
+    assert!(lines.contains(&"sozu_backend_active_connections{app_id=\"app_2\",backend_id=\"app_2-0\",address=\"127.0.0.1:1261\"} 0"));
+    assert!(lines.contains(&"sozu_backend_failures{app_id=\"app_1\",backend_id=\"app_1-0\",address=\"127.0.0.1:1260\"} 0"));
+    assert!(lines.contains(&"sozu_backend_available{app_id=\"app_2\",backend_id=\"app_2-0\",address=\"127.0.0.1:1261\"} 1"));
+  }
+
+  #[test]
+  fn it_should_ask_for_a_retry_during_the_single_backend_grace() {
+    let now = Rc::new(Cell::new(Instant::now()));
+    let clock = now.clone();
+    let mut backend_map = BackendMap::with_clock(move || clock.get());
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1270".parse().unwrap();
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+    backend_map.set_single_backend_grace_for_app(app_id, Some(Duration::from_millis(50)));
+    backend_map.report_health(app_id, &address, false);
+
+    assert_eq!(Err(ConnectionError::RetryLater(Duration::from_millis(50))), backend_map.backend_from_app_id(app_id).map(|_| ()));
+
+    now.set(now.get() + Duration::from_millis(49));
+    assert_eq!(Err(ConnectionError::RetryLater(Duration::from_millis(1))), backend_map.backend_from_app_id(app_id).map(|_| ()));
+
+    now.set(now.get() + Duration::from_millis(1));
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
+  }
+
+  #[test]
+  fn it_should_use_the_fallback_before_the_single_backend_grace() {
+    let mut backend_map = BackendMap::new();
+    let address: SocketAddr = "127.0.0.1:1271".parse().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let fallback_address = listener.local_addr().unwrap();
+
+    backend_map.add_backend("primary", Backend::new("primary-1", address, None, None, None));
+    backend_map.add_backend("secondary", Backend::new("secondary-1", fallback_address, None, None, None));
+    backend_map.set_single_backend_grace_for_app("primary", Some(Duration::from_secs(10)));
+    backend_map.set_fallbacks_for_app("primary", vec![FallbackTarget::App("secondary".to_string())]);
+    backend_map.report_health("primary", &address, false);
+
+    let (backend, _) = backend_map.backend_from_app_id("primary").unwrap();
+    assert_eq!(fallback_address, backend.borrow().address);
+
+    backend_map.report_health("secondary", &fallback_address, false);
+    match backend_map.backend_from_app_id("primary") {
+      Err(ConnectionError::RetryLater(_)) => {},
+      _ => panic!("expected a retry signal once the fallback is unavailable"),
+    }
+  }
+
+  #[test]
+  fn it_should_refuse_selection_when_too_many_connection_attempts_are_in_flight() {
+    let mut backend_map = BackendMap::new();
+    backend_map.add_backend("app_1", Backend::new("app_1-0", "127.0.0.1:1280".parse().unwrap(), None, None, None));
+    backend_map.add_backend("app_2", Backend::new("app_2-0", "127.0.0.1:1281".parse().unwrap(), None, None, None));
+    backend_map.set_max_connection_attempts(Some(2));
+
+    assert!(backend_map.backend_from_app_id("app_1").is_ok());
+    assert!(backend_map.backend_from_app_id("app_2").is_ok());
+    assert_eq!(Err(ConnectionError::TooManyConnections), backend_map.backend_from_app_id("app_1").map(|_| ()));
+    assert_eq!(Err(ConnectionError::TooManyConnections), backend_map.backend_from_app_id("app_2").map(|_| ()));
+
+    backend_map.connection_attempt_resolved();
+    assert!(backend_map.backend_from_app_id("app_2").is_ok());
+  }
+
+  #[test]
+  fn it_should_only_count_connection_attempts_while_limited() {
+    let mut backend_map = BackendMap::new();
+    backend_map.add_backend("app_1", Backend::new("app_1-0", "127.0.0.1:1282".parse().unwrap(), None, None, None));
+
+    for _ in 0..5 {
+      assert!(backend_map.backend_from_app_id("app_1").is_ok());
+    }
+    assert_eq!(0, backend_map.connection_attempts);
+
+    backend_map.set_max_connection_attempts(Some(1));
+    assert!(backend_map.backend_from_app_id("app_1").is_ok());
+    assert_eq!(Err(ConnectionError::TooManyConnections), backend_map.backend_from_app_id("app_1").map(|_| ()));
+
+    backend_map.set_max_connection_attempts(None);
+    assert_eq!(0, backend_map.connection_attempts);
+  }
+
+  #[test]
+  fn it_should_rebalance_weights_toward_less_loaded_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1290".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1291".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-3", "127.0.0.1:1292".parse().unwrap(), None, Some(LoadBalancingParams { weight: 50 }), None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::WeightedRandom);
+
+    for (backend, connections) in backend_map.backends[app_id].backends.iter().zip([1, 9, 29].iter()) {
+      backend.borrow_mut().active_connections = *connections;
+    }
+
+    backend_map.rebalance_weights(app_id);
+
+    let weights: Vec<u8> = backend_map.backends[app_id].backends.iter().map(|b| b.borrow().weight()).collect();
+    assert_eq!(vec![100, 20, 6], weights);
+
+    backend_map.reset_weights(app_id);
+    let weights: Vec<u8> = backend_map.backends[app_id].backends.iter().map(|b| b.borrow().weight()).collect();
+    assert_eq!(vec![1, 1, 50], weights);
+  }
+
+  #[test]
+  fn it_should_not_rebalance_weights_unused_by_the_policy() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1293".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1294".parse().unwrap(), None, None, None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::RoundRobin);
+    backend_map.backends[app_id].backends[1].borrow_mut().active_connections = 10;
+
+    backend_map.rebalance_weights(app_id);
+
+    assert!(backend_map.backends[app_id].backends.iter().all(|b| b.borrow().rebalanced_weight.is_none()));
+  }
+
+  #[test]
+  fn it_should_store_a_sticky_cookie_name_per_app() {
+    let mut backend_map = BackendMap::new();
+    backend_map.set_sticky_cookie_name_for_app("app_1", Some("APP1ID".to_string()));
+    backend_map.add_backend("app_2", Backend::new("app_2-0", "127.0.0.1:1300".parse().unwrap(), None, None, None));
+
+    assert_eq!("APP1ID", backend_map.sticky_cookie_name_for_app("app_1"));
+    assert_eq!(proxy::default_sticky_name(), backend_map.sticky_cookie_name_for_app("app_2"));
+    assert_eq!(proxy::default_sticky_name(), backend_map.sticky_cookie_name_for_app("unknown"));
+  }
+
+  #[test]
+  fn it_should_not_try_any_backend_once_the_deadline_is_exceeded() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1310".parse().unwrap(), None, None, None));
+
+    let deadline = Instant::now();
+    assert_eq!(Err(ConnectionError::DeadlineExceeded),
+      backend_map.backend_from_app_id_with_deadline(app_id, deadline).map(|_| ()));
+
+    let backend = backend_map.backends[app_id].backends[0].borrow();
+    assert_eq!(0, backend.active_connections);
+    assert_eq!(0, backend.failures);
+  }
+
+  #[test]
+  fn it_should_invalidate_a_sticky_session_after_repeated_fallbacks() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let sticky_address: SocketAddr = "127.0.0.1:1320".parse().unwrap();
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", sticky_address, Some("server-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1321".parse().unwrap(), Some("server-2".to_string()), None, None));
+    backend_map.set_max_sticky_fallbacks_for_app(app_id, Some(3));
+    backend_map.report_health(app_id, &sticky_address, false);
+
+    for _ in 0..2 {
+      let (backend, _) = backend_map.backend_from_sticky_session(app_id, "server-1").unwrap();
+      assert!(backend.borrow().address != sticky_address);
+      assert!(!backend_map.is_sticky_session_invalidated(app_id, "server-1"));
+    }
+
+    assert!(backend_map.backend_from_sticky_session(app_id, "server-1").is_ok());
+    assert!(backend_map.is_sticky_session_invalidated(app_id, "server-1"));
+    assert_eq!(None, backend_map.backends[app_id].sticky_fallbacks.get("server-1"));
+
+    backend_map.remove_backend(app_id, &sticky_address);
+    assert!(!backend_map.is_sticky_session_invalidated(app_id, "server-1"));
+  }
+
+  #[test]
+  fn it_should_not_track_sticky_sessions_of_unknown_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1322".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backend_map.set_max_sticky_fallbacks_for_app(app_id, Some(1));
+
+    for i in 0..100 {
+      assert!(backend_map.backend_from_sticky_session(app_id, &format!("random-{}", i)).is_ok());
+    }
+
+    assert!(backend_map.backends[app_id].sticky_fallbacks.is_empty());
+    assert!(backend_map.backends[app_id].invalid_sticky_sessions.is_empty());
+  }
+
+  #[test]
+  fn it_should_select_a_backend_without_connecting_to_it() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1330".parse().unwrap(), None, None, None));
+
+    let backend = backend_map.select_backend(app_id).unwrap();
+    assert_eq!("myapp-1", backend.borrow().backend_id);
+    assert_eq!(0, backend.borrow().active_connections);
+    assert_eq!(0, backend_map.connection_attempts);
+
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.select_backend("unknown").map(|_| ()));
+  }
+
+  #[test]
+  fn it_should_resolve_colliding_sticky_ids_to_the_first_openable_backend() {
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:1340".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backends_list.add_backend(Backend::new("myback-2", "127.0.0.1:1341".parse().unwrap(), Some("server-1".to_string()), None, None));
+
+    assert_eq!("myback-1", backends_list.find_sticky("server-1", Instant::now()).unwrap().borrow().backend_id);
+
+    backends_list.backends[0].borrow_mut().healthy = Some(false);
+    assert_eq!("myback-2", backends_list.find_sticky("server-1", Instant::now()).unwrap().borrow().backend_id);
+
+    backends_list.backends[1].borrow_mut().healthy = Some(false);
+    assert!(backends_list.find_sticky("server-1", Instant::now()).is_none());
+  }
+
+  /// polls until the connection is resolved and returns every result seen,
+  /// the pending steps must leave the failures and the attempts untouched
+  fn poll_sequence(backend_map: &mut BackendMap, backend: &Rc<RefCell<Backend>>, socket: &TcpStream) -> Vec<Result<BackendConnectionStatus, ConnectionError>> {
+    let before = (backend.borrow().failures, backend_map.connection_attempts);
+    let mut sequence = Vec::new();
+    for _ in 0..100 {
+      let res = backend_map.poll_backend_connection(backend, socket);
+      let pending = res == Ok(BackendConnectionStatus::Connecting);
+      sequence.push(res);
+      if !pending {
+        break;
+      }
+
+      assert_eq!(before, (backend.borrow().failures, backend_map.connection_attempts));
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    sequence
+  }
+
+  fn assert_resolved_to(expected: Result<BackendConnectionStatus, ConnectionError>, mut sequence: Vec<Result<BackendConnectionStatus, ConnectionError>>) {
+    assert_eq!(Some(expected), sequence.pop());
+    assert!(sequence.iter().all(|res| *res == Ok(BackendConnectionStatus::Connecting)), "unexpected steps: {:?}", sequence);
+  }
+
+  #[test]
+  fn it_should_resolve_the_connection_attempt_once_connected() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myback", listener.local_addr().unwrap(), None, None, None));
+    backend_map.set_max_connection_attempts(Some(1));
+
+    let (backend, socket) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!(Some(ConnectionError::TooManyConnections), backend_map.backend_from_app_id(app_id).err());
+
+    assert_resolved_to(Ok(BackendConnectionStatus::Connected), poll_sequence(&mut backend_map, &backend, &socket));
+    assert_eq!(0, backend_map.connection_attempts);
+    assert_eq!(1, backend.borrow().active_connections);
+    assert_eq!(0, backend.borrow().failures);
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
+  }
+
+  #[test]
+  fn it_should_count_a_hang_up_before_the_connection_completed() {
+    let now = Instant::now();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut backend_map = BackendMap::with_clock(move || now);
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myback", listener.local_addr().unwrap(), None, None, None));
+    backend_map.set_circuit_breaker(Some(CircuitBreaker { max_failures: 1, cooldown: Duration::from_secs(30) }));
+
+    // what the session does when the socket hangs up while poll_connect still sees it connecting
+    let (backend, _socket) = backend_map.backend_from_app_id(app_id).unwrap();
+    backend_map.backend_connection_failed(&backend);
+    assert_eq!(1, backend.borrow().failures);
+    assert_eq!(CircuitBreakerState::Open(now + Duration::from_secs(30)), backend.borrow().circuit_breaker);
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
+  }
+
+  #[test]
+  fn it_should_keep_a_refused_connection_attempt_until_the_session_closes_it() {
+    // bind then drop a listener to get a local port nobody listens on
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myback", address, None, None, None));
+    backend_map.set_max_connection_attempts(Some(1));
+
+    let (backend, socket) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_resolved_to(Err(ConnectionError::NoBackendAvailable), poll_sequence(&mut backend_map, &backend, &socket));
+    assert_eq!(1, backend.borrow().failures);
+    assert_eq!(1, backend_map.connection_attempts);
+
+    // what the session does when it closes the failed connection
+    backend.borrow_mut().dec_connections();
+    backend_map.connection_attempt_resolved();
+    assert_eq!(0, backend.borrow().active_connections);
+    assert_eq!(0, backend_map.connection_attempts);
+  }
+
+  #[test]
+  fn it_should_run_a_policy_over_the_given_candidates_only() {
+    let mut backends_list = BackendList::new();
+    for (i, connections) in [0, 5, 3, 8].iter().enumerate() {
+      let address = format!("127.0.0.1:{}", 1350 + i).parse().unwrap();
+      backends_list.add_backend(Backend::new(&format!("myback-{}", i), address, None, None, None));
+      backends_list.backends[i].borrow_mut().active_connections = *connections;
+    }
+
+    let mut least_connections = LeastConnectionsAlgorithm;
+    let backend = backends_list.select_from(&[1, 2, 3], &mut least_connections).unwrap();
+    assert_eq!("myback-2", backend.borrow().backend_id);
+
+    assert!(backends_list.select_from(&[10], &mut least_connections).is_none());
+
+    let mut round_robin = RoundRobinAlgorithm { next_backend: 0 };
+    let picks: Vec<String> = (0..4)
+      .map(|_| backends_list.select_from(&[0, 3], &mut round_robin).unwrap().borrow().backend_id.clone())
+      .collect();
+    assert_eq!(vec!["myback-0", "myback-3", "myback-0", "myback-3"], picks);
+  }
+
+  #[test]
+  fn it_should_track_connect_and_request_times_independently() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1360".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+
+    backend_map.report_connect_time(app_id, &address, Duration::from_millis(4));
+    backend_map.report_connect_time(app_id, &address, Duration::from_millis(8));
+    backend_map.report_request_time(app_id, &address, Duration::from_millis(100));
+
+    let backend = backend_map.backends[app_id].backends[0].borrow();
+    assert_eq!(Some(Duration::from_millis(5)), backend.connect_time);
+    assert_eq!(Some(Duration::from_millis(100)), backend.request_time);
+  }
+
+  #[test]
+  fn it_should_refuse_selection_until_a_transaction_is_committed() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let old_address: SocketAddr = "127.0.0.1:1370".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", old_address, None, None, None));
+    backend_map.set_reject_during_reconfiguration_for_app(app_id, true);
+
+    backend_map.begin_transaction(app_id);
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1371".parse().unwrap(), None, None, None));
+    backend_map.remove_backend(app_id, &old_address);
+
+    assert_eq!(Err(ConnectionError::Reconfiguring), backend_map.backend_from_app_id(app_id).map(|_| ()));
+    assert_eq!(Err(ConnectionError::Reconfiguring), backend_map.select_backend(app_id).map(|_| ()));
+    assert_eq!(1, backend_map.backends[app_id].backends.len());
+
+    backend_map.commit_transaction(app_id);
+
+    assert_eq!(1, backend_map.backends[app_id].backends.len());
+    assert_eq!("myapp-2", backend_map.select_backend(app_id).unwrap().borrow().backend_id);
+  }
+
+  #[test]
+  fn it_should_serve_the_previous_backends_during_a_transaction() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let old_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let new_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let old_address = old_listener.local_addr().unwrap();
+    let new_address = new_listener.local_addr().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", old_address, None, None, None));
+
+    backend_map.begin_transaction(app_id);
+    backend_map.add_backend(app_id, Backend::new("myapp-2", new_address, None, None, None));
+    backend_map.remove_backend(app_id, &old_address);
+
+    for _ in 0..3 {
+      let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+      assert_eq!(old_address, backend.borrow().address);
+    }
+
+    backend_map.commit_transaction(app_id);
+
+    for _ in 0..3 {
+      let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+      assert_eq!(new_address, backend.borrow().address);
+    }
+  }
+
+  #[test]
+  fn it_should_retry_on_other_backends_until_the_budget_is_exhausted() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let failed_address: SocketAddr = "127.0.0.1:1380".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", failed_address, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1381".parse().unwrap(), None, None, None));
+    backend_map.set_retry_budget_for_app(app_id, 2);
+
+    let mut token = RetryToken::default();
+    for _ in 0..2 {
+      let (backend, _) = backend_map.retry_backend(app_id, &failed_address, &mut token).unwrap();
+      assert!(backend.borrow().address != failed_address);
+    }
+
+    assert_eq!(2, token.retries);
+    assert_eq!(Err(ConnectionError::TooManyRetries),
+      backend_map.retry_backend(app_id, &failed_address, &mut token).map(|_| ()));
+  }
+
+  #[test]
+  fn it_should_list_the_most_loaded_backends_across_apps() {
+    let mut backend_map = BackendMap::new();
+    let loads = [("app_1", "127.0.0.1:1390", 4), ("app_1", "127.0.0.1:1391", 12),
+      ("app_2", "127.0.0.1:1392", 7), ("app_2", "127.0.0.1:1393", 1)];
+    for (i, &(app_id, address, connections)) in loads.iter().enumerate() {
+      let mut backend = Backend::new(&format!("backend-{}", i), address.parse().unwrap(), None, None, None);
+      backend.active_connections = connections;
+      backend_map.add_backend(app_id, backend);
+    }
+
+    let expected: Vec<(AppId, SocketAddr, usize)> = vec![
+      ("app_1".to_string(), "127.0.0.1:1391".parse().unwrap(), 12),
+      ("app_2".to_string(), "127.0.0.1:1392".parse().unwrap(), 7),
+      ("app_1".to_string(), "127.0.0.1:1390".parse().unwrap(), 4),
+    ];
+    assert_eq!(expected, backend_map.hottest_backends(3));
+  }
+
+  #[test]
+  fn it_should_route_sticky_sessions_to_draining_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1400".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1401".parse().unwrap(), Some("server-2".to_string()), None, None));
+
+    {
+      let app_backends = backend_map.backends.get_mut(app_id).unwrap();
+      app_backends.backends[0].borrow_mut().active_connections = 1;
+      app_backends.backends[0].borrow_mut().set_closing();
+    }
+
+    let (backend, _) = backend_map.backend_from_sticky_session(app_id, "server-1").unwrap();
+    assert_eq!("myapp-1", backend.borrow().backend_id);
+    assert_eq!(2, backend.borrow().active_connections);
+
+    for _ in 0..10 {
+      let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+      assert_eq!("myapp-2", backend.borrow().backend_id);
+    }
+
+    backend_map.set_sticky_draining_grace_for_app(app_id, false);
+    let (backend, _) = backend_map.backend_from_sticky_session(app_id, "server-1").unwrap();
+    assert_eq!("myapp-2", backend.borrow().backend_id);
+  }
+
+  #[test]
+  fn it_should_report_the_sticky_id_matched_by_find_sticky() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1410".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1411".parse().unwrap(), None, None, None));
+
+    for _ in 0..10 {
+      let backend = backend_map.select_backend(app_id).unwrap();
+      let sticky_id = backend.borrow().sticky_session_id().to_string();
+      let expected = if backend.borrow().backend_id == "myapp-1" { "server-1" } else { "myapp-2" };
+      assert_eq!(expected, sticky_id);
+
+      let app_backends = backend_map.backends.get_mut(app_id).unwrap();
+      let sticky_backend = app_backends.find_sticky(&sticky_id, Instant::now()).unwrap();
+      assert_eq!(backend.borrow().address, sticky_backend.borrow().address);
+    }
+  }
+
+  #[derive(Debug)]
+  struct LastBackendAlgorithm {
+    calls: Rc<Cell<usize>>,
+  }
+
+  impl LoadBalancingAlgorithm for LastBackendAlgorithm {
+    fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
+      self.calls.set(self.calls.get() + 1);
+      backends.last().cloned()
+    }
+  }
+
+  #[test]
+  fn it_should_select_backends_with_a_custom_load_balancing_algorithm() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1420".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1421".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-3", "127.0.0.1:1422".parse().unwrap(), None, None, None));
+
+    let calls = Rc::new(Cell::new(0));
+    backend_map.set_load_balancing_algorithm_for_app(app_id, Box::new(LastBackendAlgorithm { calls: calls.clone() }));
+
+    for _ in 0..5 {
+      let backend = backend_map.select_backend(app_id).unwrap();
+      assert_eq!("myapp-3", backend.borrow().backend_id);
+    }
+    assert_eq!(5, calls.get());
+  }
+
+  #[test]
+  fn it_should_follow_percentage_weights() {
+    let percentages = vec![
+      BackendConfig { address: "127.0.0.1:1430".parse().unwrap(), weight: None, weight_percentage: Some(70), sticky_id: None, backup: None },
+      BackendConfig { address: "127.0.0.1:1431".parse().unwrap(), weight: None, weight_percentage: Some(30), sticky_id: None, backup: None },
+    ];
+    let weights = backend_weights("myapp", &percentages);
+
+    let backends: Vec<proxy::Backend> = percentages.iter().zip(weights).enumerate().map(|(i, (backend, weight))| {
+      let mut config = config_backend("myapp", &format!("myapp-{}", i), &backend.address.to_string());
+      config.load_balancing_parameters = Some(LoadBalancingParams { weight });
+      config
+    }).collect();
+
+    let mut backends_list = BackendList::import_configuration_state(&backends);
+    backends_list.set_load_balancing_policy(LoadBalancingAlgorithms::WeightedRandom);
+
+    let mut first = 0;
+    for _ in 0..10000 {
+      if backends_list.next_available_backend().unwrap().borrow().backend_id == "myapp-0" {
+        first += 1;
+      }
+    }
+    assert!(first > 6700 && first < 7300, "myapp-0 was selected {} times out of 10000", first);
+  }
+
+  #[test]
+  fn it_should_apply_health_checks_per_app() {
+    let mut backend_map = BackendMap::new();
+    let http_address: SocketAddr = "127.0.0.1:1440".parse().unwrap();
+    let tcp_address: SocketAddr = "127.0.0.1:1441".parse().unwrap();
+    backend_map.add_backend("http_app", Backend::new("http_app-1", http_address, None, None, None));
+    backend_map.add_backend("tcp_app", Backend::new("tcp_app-1", tcp_address, None, None, None));
+
+    let http_check = HealthCheckConfig {
+      kind:                HealthCheckKind::Http { path: "/healthz".to_string(), expected_status: 200 },
+      interval:            Duration::from_secs(10),
+      timeout:             Duration::from_secs(2),
+      unhealthy_threshold: 3,
+      healthy_threshold:   2,
+    };
+    let tcp_check = HealthCheckConfig {
+      kind:                HealthCheckKind::Tcp,
+      interval:            Duration::from_secs(5),
+      timeout:             Duration::from_secs(1),
+      unhealthy_threshold: 1,
+      healthy_threshold:   1,
+    };
+    backend_map.set_health_check_for_app("http_app", Some(http_check.clone()));
+    backend_map.set_health_check_for_app("tcp_app", Some(tcp_check.clone()));
+    assert_eq!(Some(&http_check), backend_map.health_check_for_app("http_app"));
+    assert_eq!(Some(&tcp_check), backend_map.health_check_for_app("tcp_app"));
+
+    let healthy = |backend_map: &mut BackendMap, app_id: &str, address: &SocketAddr| {
+      backend_map.backends.get_mut(app_id).unwrap().find_backend(address).unwrap().borrow().healthy
+    };
+
+    backend_map.report_health_check("http_app", &http_address, false);
+    backend_map.report_health_check("tcp_app", &tcp_address, false);
+    assert_eq!(None, healthy(&mut backend_map, "http_app", &http_address));
+    assert_eq!(Some(false), healthy(&mut backend_map, "tcp_app", &tcp_address));
+
+    backend_map.report_health_check("http_app", &http_address, false);
+    backend_map.report_health_check("http_app", &http_address, false);
+    assert_eq!(Some(false), healthy(&mut backend_map, "http_app", &http_address));
+
+    backend_map.report_health_check("http_app", &http_address, true);
+    backend_map.report_health_check("tcp_app", &tcp_address, true);
+    assert_eq!(Some(false), healthy(&mut backend_map, "http_app", &http_address));
+    assert_eq!(Some(true), healthy(&mut backend_map, "tcp_app", &tcp_address));
+
+    backend_map.report_health_check("http_app", &http_address, true);
+    assert_eq!(Some(true), healthy(&mut backend_map, "http_app", &http_address));
+  }
+
+  #[test]
+  fn it_should_dampen_flapping_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1450".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+    backend_map.set_flap_detection_for_app(app_id, Some(FlapDetection {
+      window:          Duration::from_secs(60),
+      max_transitions: 4,
+      dampening:       Duration::from_secs(300),
+    }));
+
+    backend_map.report_health(app_id, &address, true);
+    for _ in 0..2 {
+      backend_map.report_health(app_id, &address, false);
+      backend_map.report_health(app_id, &address, true);
+    }
+    {
+      let backend = backend_map.backends[app_id].backends[0].borrow();
+      assert!(!backend.is_flapping());
+      assert!(backend.can_open());
+    }
+
+    backend_map.report_health(app_id, &address, false);
+    backend_map.report_health(app_id, &address, true);
+    {
+      let backend = backend_map.backends[app_id].backends[0].borrow();
+      assert_eq!(Some(true), backend.healthy);
+      assert!(backend.is_flapping());
+      assert!(!backend.can_open());
+    }
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.select_backend(app_id).map(|_| ()));
+
+    let mut output = Vec::new();
+    backend_map.export_metrics(&mut output, MetricsFormat::Prometheus).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.lines().any(|line| line == "sozu_backend_flapping{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1450\"} 1"));
+  }
+
+  fn policy_map(policy: LoadBalancingAlgorithms) -> BackendMap {
+    let mut backend_map = BackendMap::with_policy(policy);
+    for (i, connections) in [5, 1, 3].iter().enumerate() {
+      let address = format!("127.0.0.1:{}", 1460 + i).parse().unwrap();
+      backend_map.add_backend("myapp", Backend::new(&format!("myapp-{}", i), address, None, None, None));
+      backend_map.backends["myapp"].backends[i].borrow_mut().active_connections = *connections;
+    }
+    backend_map
+  }
+
+  fn selection_counts(backend_map: &mut BackendMap, selections: usize) -> Vec<usize> {
+    let mut counts = vec![0; 3];
+    for _ in 0..selections {
+      let backend = backend_map.select_backend("myapp").unwrap();
+      let index = backend_map.backends["myapp"].backends.iter().position(|b| Rc::ptr_eq(b, &backend)).unwrap();
+      counts[index] += 1;
+    }
+    counts
+  }
+
+  #[test]
+  fn it_should_apply_the_map_default_load_balancing_policy() {
+    let mut backend_map = policy_map(LoadBalancingAlgorithms::RoundRobin);
+    let order: Vec<String> = (0..6).map(|_| backend_map.select_backend("myapp").unwrap().borrow().backend_id.clone()).collect();
+    assert_eq!(vec!["myapp-0", "myapp-1", "myapp-2", "myapp-0", "myapp-1", "myapp-2"], order);
+
+    let mut backend_map = policy_map(LoadBalancingAlgorithms::LeastConnections);
+    assert_eq!(vec![0, 100, 0], selection_counts(&mut backend_map, 100));
+
+    let mut backend_map = policy_map(LoadBalancingAlgorithms::Random);
+    for count in selection_counts(&mut backend_map, 3000) {
+      assert!(count > 800 && count < 1200, "a backend was selected {} times out of 3000", count);
+    }
+  }
+
+  #[test]
+  fn it_should_select_backends_proportionally_to_their_weight() {
+    let mut backends_list = BackendList::with_policy(LoadBalancingAlgorithms::WeightedRandom);
+    backends_list.add_backend_weighted("myback-1", "127.0.0.1:1470".parse().unwrap(), 4);
+    backends_list.add_backend_weighted("myback-2", "127.0.0.1:1471".parse().unwrap(), 1);
+    backends_list.add_backend_weighted("myback-3", "127.0.0.1:1472".parse().unwrap(), 0);
+    backends_list.add_backend_weighted("myback-4", "127.0.0.1:1473".parse().unwrap(), 4);
+    backends_list.backends[3].borrow_mut().healthy = Some(false);
+
+    let mut counts = HashMap::new();
+    for _ in 0..5000 {
+      let backend = backends_list.next_available_backend().unwrap();
+      *counts.entry(backend.borrow().backend_id.clone()).or_insert(0) += 1;
+    }
+
+    let first = counts.get("myback-1").cloned().unwrap_or(0);
+    assert!(first > 3800 && first < 4200, "myback-1 was selected {} times out of 5000", first);
+    assert_eq!(5000 - first, counts.get("myback-2").cloned().unwrap_or(0));
+    assert!(!counts.contains_key("myback-3"));
+    assert!(!counts.contains_key("myback-4"));
+  }
+
+  #[test]
+  fn it_should_count_connection_churn() {
+    let now = Rc::new(Cell::new(Instant::now()));
+    let clock = now.clone();
+    let mut backend_map = BackendMap::with_clock(move || clock.get());
+    let address: SocketAddr = "127.0.0.1:1480".parse().unwrap();
+    backend_map.add_backend("myapp", Backend::new("myapp-1", address, None, None, None));
+
+    for _ in 0..20 {
+      backend_map.backends["myapp"].backends[0].borrow_mut().inc_connections();
+      backend_map.close_backend_connection("myapp", &address);
+    }
+    backend_map.backends["myapp"].backends[0].borrow_mut().inc_connections();
+
+    let export = |backend_map: &BackendMap| -> Vec<String> {
+      let mut output = Vec::new();
+      backend_map.export_metrics(&mut output, MetricsFormat::Prometheus).unwrap();
+      String::from_utf8(output).unwrap().lines().map(|line| line.to_string()).collect()
+    };
+
+    now.set(Instant::now());
+    let lines = export(&backend_map);
+    assert!(lines.contains(&"# TYPE sozu_backend_connections_opened counter".to_string()));
+    assert!(lines.contains(&"# TYPE sozu_backend_connections_opened_per_minute gauge".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_opened{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 21".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_closed{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 20".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_opened_per_minute{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 21".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_closed_per_minute{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 20".to_string()));
+    assert!(lines.contains(&"sozu_backend_active_connections{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 1".to_string()));
+
+    // a minute later the churn is gone, the totals stay
+    now.set(now.get() + Duration::from_secs(61));
+    let lines = export(&backend_map);
+    assert!(lines.contains(&"sozu_backend_connections_opened{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 21".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_opened_per_minute{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 0".to_string()));
+    assert!(lines.contains(&"sozu_backend_connections_closed_per_minute{app_id=\"myapp\",backend_id=\"myapp-1\",address=\"127.0.0.1:1480\"} 0".to_string()));
+  }
+
+  #[test]
+  fn it_should_only_count_the_last_minute_in_a_rolling_counter() {
+    let origin = Instant::now();
+    let mut counter = RollingCounter::new(origin);
+    counter.add(3, origin);
+    counter.add(2, origin + Duration::from_secs(30));
+    assert_eq!(5, counter.count_at(origin + Duration::from_secs(30)));
+    assert_eq!(5, counter.count_at(origin + Duration::from_secs(59)));
+    assert_eq!(2, counter.count_at(origin + Duration::from_secs(60)));
+
+    // the buckets of the first minute are reused
+    counter.add(4, origin + Duration::from_secs(75));
+    assert_eq!(6, counter.count_at(origin + Duration::from_secs(75)));
+    assert_eq!(4, counter.count_at(origin + Duration::from_secs(90)));
+    assert_eq!(0, counter.count_at(origin + Duration::from_secs(135)));
+
+    counter.add(1, origin + Duration::from_secs(300));
+    assert_eq!(1, counter.count_at(origin + Duration::from_secs(300)));
+  }
+
+  #[test]
+  fn it_should_try_another_backend_when_a_connection_fails() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let dead_address: SocketAddr = "255.255.255.255:80".parse().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let live_address = listener.local_addr().unwrap();
+
+    backend_map.add_backend(app_id, Backend::new("myapp-dead", dead_address, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-live", live_address, None, None, None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::RoundRobin);
+
+    let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!("myapp-live", backend.borrow().backend_id);
+    assert_eq!(1, backend_map.backends[app_id].backends[0].borrow().failures);
+
+    backend_map.remove_backend(app_id, &live_address);
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
+  }
+
+  #[test]
+  fn it_should_walk_the_fallback_chain() {
+    let mut backend_map = BackendMap::new();
+    let primary_address: SocketAddr = "127.0.0.1:1490".parse().unwrap();
+    let secondary_address: SocketAddr = "127.0.0.1:1491".parse().unwrap();
+    backend_map.add_backend("primary", Backend::new("primary-1", primary_address, None, None, None));
+    backend_map.add_backend("secondary", Backend::new("secondary-1", secondary_address, None, None, None));
+
+    let maintenance = Rc::new(RefCell::new(Backend::new("maintenance", "127.0.0.1:1492".parse().unwrap(), None, None, None)));
+    backend_map.set_fallbacks_for_app("primary", vec![
+      FallbackTarget::App("secondary".to_string()),
+      FallbackTarget::Maintenance(maintenance.clone()),
+    ]);
+    backend_map.set_fallbacks_for_app("secondary", vec![FallbackTarget::App("primary".to_string())]);
+
+    let (backend, _) = backend_map.backend_from_app_id("primary").unwrap();
+    assert_eq!("primary-1", backend.borrow().backend_id);
+
+    backend_map.report_health("primary", &primary_address, false);
+    let (backend, _) = backend_map.backend_from_app_id("primary").unwrap();
+    assert_eq!("secondary-1", backend.borrow().backend_id);
+
+    backend_map.report_health("secondary", &secondary_address, false);
+    let (backend, _) = backend_map.backend_from_app_id("primary").unwrap();
+    assert!(Rc::ptr_eq(&maintenance, &backend));
+
+    maintenance.borrow_mut().healthy = Some(false);
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id("primary").map(|_| ()));
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id("secondary").map(|_| ()));
   }
 
-  pub fn remove_backend(&mut self, app_id: &str, backend_address: &SocketAddr) {
-    if let Some(backends) = self.backends.get_mut(app_id) {
-      backends.remove_backend(backend_address);
-    } else {
-      error!("Backend was already removed: app id {}, address {:?}", app_id, backend_address);
+  #[test]
+  fn it_should_reenable_a_backend_after_the_circuit_breaker_cooldown() {
+    let now = Rc::new(Cell::new(Instant::now()));
+    let clock = now.clone();
+    let mut backend_map = BackendMap::with_clock(move || clock.get());
+    let app_id = "myapp";
+    let dead_address: SocketAddr = "255.255.255.255:80".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", dead_address, None, None, None));
+    backend_map.set_circuit_breaker(Some(CircuitBreaker { max_failures: 2, cooldown: Duration::from_secs(30) }));
+
+    for _ in 0..2 {
+      backend_map.backends[app_id].backends[0].borrow_mut().retry_policy().succeed();
+      assert!(backend_map.backend_from_app_id(app_id).is_err());
     }
+
+    let backend = backend_map.backends[app_id].backends[0].clone();
+    assert_eq!(2, backend.borrow().failures);
+    assert_eq!(CircuitBreakerState::Open(now.get() + Duration::from_secs(30)), backend.borrow().circuit_breaker);
+
+    // the backend came back, but stays out of the rotation during the cooldown
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    backend.borrow_mut().address = listener.local_addr().unwrap();
+    backend.borrow_mut().retry_policy().succeed();
+    now.set(now.get() + Duration::from_secs(10));
+    assert!(backend_map.backend_from_app_id(app_id).is_err());
+
+    // once it is over, a single trial connection is let through
+    now.set(now.get() + Duration::from_secs(21));
+    let (trial, socket) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!(CircuitBreakerState::HalfOpen, trial.borrow().circuit_breaker);
+    assert_eq!(2, trial.borrow().failures);
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
+
+    // and it only closes the breaker once it is confirmed
+    assert_resolved_to(Ok(BackendConnectionStatus::Connected), poll_sequence(&mut backend_map, &trial, &socket));
+    assert_eq!(0, trial.borrow().failures);
+    assert_eq!(CircuitBreakerState::Closed, trial.borrow().circuit_breaker);
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
   }
 
-  pub fn close_backend_connection(&mut self, app_id: &str, addr: &SocketAddr) {
-    if let Some(app_backends) = self.backends.get_mut(app_id) {
-      if let Some(ref mut backend) = app_backends.find_backend(addr) {
-        (*backend.borrow_mut()).dec_connections();
-      }
+  #[test]
+  fn it_should_trip_the_circuit_breaker_from_sticky_and_refused_connections() {
+    let now = Instant::now();
+    let mut backend_map = BackendMap::with_clock(move || now);
+    let app_id = "myapp";
+    let dead_address: SocketAddr = "255.255.255.255:80".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", dead_address, Some("server-1".to_string()), None, None));
+    backend_map.set_circuit_breaker(Some(CircuitBreaker { max_failures: 2, cooldown: Duration::from_secs(30) }));
+
+    let backend = backend_map.backends[app_id].backends[0].clone();
+    for _ in 0..2 {
+      backend.borrow_mut().retry_policy().succeed();
+      assert!(backend_map.backend_from_sticky_session(app_id, "server-1").is_err());
     }
+    assert_eq!(CircuitBreakerState::Open(now + Duration::from_secs(30)), backend.borrow().circuit_breaker);
+
+    // bind then drop a listener to get a local port nobody listens on
+    let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-2", refused, None, None, None));
+    backend_map.set_circuit_breaker(Some(CircuitBreaker { max_failures: 1, cooldown: Duration::from_secs(30) }));
+
+    let (backend, socket) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!(refused, backend.borrow().address);
+    assert_resolved_to(Err(ConnectionError::NoBackendAvailable), poll_sequence(&mut backend_map, &backend, &socket));
+    assert_eq!(CircuitBreakerState::Open(now + Duration::from_secs(30)), backend.borrow().circuit_breaker);
+    assert!(!backend.borrow().can_open_at(now));
   }
 
-  pub fn has_backend(&self, app_id: &str, backend: &Backend) -> bool {
-    self.backends.get(app_id).map(|backends| {
-      backends.has_backend(&backend.address)
-    }).unwrap_or(false)
+  #[test]
+  fn it_should_import_ipv4_and_ipv6_backends() {
+    let backends = vec![
+      config_backend("myapp", "myapp-0", "127.0.0.1:8080"),
+      config_backend("myapp", "myapp-1", "[::1]:8080"),
+      config_backend("myapp", "myapp-2", "[2001:db8::1]:443"),
+    ];
+
+    let backends_list = BackendList::import_configuration_state(&backends);
+    let addresses: Vec<SocketAddr> = backends_list.backends.iter().map(|b| b.borrow().address).collect();
+    assert_eq!(3, addresses.len());
+    assert!(addresses[0].is_ipv4());
+    assert_eq!(8080, addresses[1].port());
+    assert!(addresses[1].ip().is_loopback() && addresses[1].is_ipv6());
+    assert_eq!("[2001:db8::1]:443", addresses[2].to_string());
   }
 
-  pub fn backend_from_app_id(&mut self, app_id: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
-    if let Some(ref mut app_backends) = self.backends.get_mut(app_id) {
-      if app_backends.backends.is_empty() {
-        self.available = false;
-        return Err(ConnectionError::NoBackendAvailable);
-      }
+  #[test]
+  fn it_should_describe_the_last_selection_as_span_attributes() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1500".parse().unwrap(), Some("server-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1501".parse().unwrap(), Some("server-2".to_string()), None, None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::LeastConnections);
+    assert!(backend_map.selection_attributes().is_empty());
 
-      if let Some(ref mut b) = app_backends.next_available_backend() {
-        let ref mut backend = *b.borrow_mut();
+    backend_map.backend_from_sticky_session(app_id, "server-2").unwrap();
+    assert_eq!(vec![
+      ("sozu.app_id", "myapp".to_string()),
+      ("sozu.backend.address", "127.0.0.1:1501".to_string()),
+      ("sozu.backend.instance_id", "myapp-2".to_string()),
+      ("sozu.lb.policy", "leastconnections".to_string()),
+      ("sozu.lb.sticky", "true".to_string()),
+      ("sozu.lb.retries", "0".to_string()),
+    ], backend_map.selection_attributes());
 
-        debug!("Connecting {} -> {:?}", app_id, (backend.address, backend.active_connections, backend.failures));
-        let conn = backend.try_connect();
+    backend_map.backend_from_app_id(app_id).unwrap();
+    let attributes = backend_map.selection_attributes();
+    assert!(attributes.contains(&("sozu.backend.instance_id", "myapp-1".to_string())));
+    assert!(attributes.contains(&("sozu.lb.sticky", "false".to_string())));
+  }
 
-        let res = conn.map(|c| {
-          (b.clone(), c)
-        }).map_err(|e| {
-          error!("could not connect {} to {:?} ({} failures)", app_id, backend.address, backend.failures);
-          e
-        });
+  #[test]
+  fn it_should_apply_the_overflow_policy_when_no_backend_is_available() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1510".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+    backend_map.report_health(app_id, &address, false);
 
-        if res.is_ok() {
-          self.available = true;
-        }
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
 
-        return res;
-      } else {
-        if self.available {
-          error!("no more available backends for app {}", app_id);
-          self.available = false;
+    backend_map.set_overflow_policy_for_app(app_id, OverflowPolicy::Queue { max_wait: Duration::from_millis(200) });
+    assert_eq!(Err(ConnectionError::RetryLater(Duration::from_millis(200))), backend_map.backend_from_app_id(app_id).map(|_| ()));
 
-          push_event(proxy::ProxyEvent::NoAvailableBackends(app_id.to_string()));
-        }
-        return Err(ConnectionError::NoBackendAvailable);
-      }
-    } else {
-      Err(ConnectionError::NoBackendAvailable)
-    }
+    let maintenance = Rc::new(RefCell::new(Backend::new("maintenance", "127.0.0.1:1511".parse().unwrap(), None, None, None)));
+    backend_map.set_overflow_policy_for_app(app_id, OverflowPolicy::Maintenance(maintenance.clone()));
+    let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert!(Rc::ptr_eq(&maintenance, &backend));
+
+    backend_map.set_overflow_policy_for_app(app_id, OverflowPolicy::FailFast);
+    assert_eq!(Err(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).map(|_| ()));
   }
 
-  pub fn backend_from_sticky_session(&mut self, app_id: &str, sticky_session: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
-    let sticky_conn: Option<Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError>> = self.backends
-      .get_mut(app_id)
-      .and_then(|app_backends| app_backends.find_sticky(sticky_session))
-      .map(|b| {
-        let ref mut backend = *b.borrow_mut();
-        let conn = backend.try_connect();
+  #[test]
+  fn it_should_read_metrics_while_a_backend_is_borrowed() {
+    let mut backend_map = BackendMap::new();
+    backend_map.add_backend("myapp", Backend::new("myapp-1", "127.0.0.1:1520".parse().unwrap(), None, None, None));
+    backend_map.add_backend("myapp", Backend::new("myapp-2", "127.0.0.1:1521".parse().unwrap(), None, None, None));
 
-        conn.map(|c| (b.clone(), c)).map_err(|e| {
-          error!("could not connect {} to {:?} using session {} ({} failures)",
-            app_id, backend.address, sticky_session, backend.failures);
-          e
-        })
-      });
+    let in_flight = backend_map.backends["myapp"].backends[0].clone();
+    let mut selected = in_flight.borrow_mut();
+    selected.active_connections = 5;
 
-    if let Some(res) = sticky_conn {
-      return res;
-    } else {
-      debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
-      return self.backend_from_app_id(app_id);
-    }
-  }
+    let mut output = Vec::new();
+    backend_map.export_metrics(&mut output, MetricsFormat::Json).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains("myapp-1"));
+    assert!(output.contains("{\"backend_id\":\"myapp-2\""));
 
-  pub fn set_load_balancing_policy_for_app(&mut self, app_id: &str, lb_algo: LoadBalancingAlgorithms) {
-    // The application can be created before the backends were registered because of the async config messages.
-    // So when we set the load balancing policy, we have to create the backend list if if it doesn't exist yet.
-    let app_backends = self.get_or_create_backend_list_for_app(app_id);
-    app_backends.set_load_balancing_policy(lb_algo);
-  }
+    let mut output = Vec::new();
+    backend_map.export_metrics(&mut output, MetricsFormat::Prometheus).unwrap();
+    assert!(!String::from_utf8(output).unwrap().contains("myapp-1"));
 
-  pub fn get_or_create_backend_list_for_app(&mut self, app_id: &str) -> &mut BackendList {
-    self.backends.entry(app_id.to_string()).or_insert_with(BackendList::new)
+    let expected: Vec<(AppId, SocketAddr, usize)> = vec![("myapp".to_string(), "127.0.0.1:1521".parse().unwrap(), 0)];
+    assert_eq!(expected, backend_map.hottest_backends(2));
+    assert_eq!(1, backend_map.backends["myapp"].available_backends(false, Instant::now()).len());
+    drop(selected);
+
+    assert_eq!(5, backend_map.hottest_backends(1)[0].2);
   }
-}
 
-#[derive(Debug)]
-pub struct BackendList {
-  pub backends:       Vec<Rc<RefCell<Backend>>>,
-  pub next_id:        u32,
-  pub load_balancing: Box<LoadBalancingAlgorithm>,
-}
+  #[test]
+  fn it_should_remove_a_draining_backend_after_its_last_connection() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let draining: SocketAddr = "127.0.0.1:1530".parse().unwrap();
+    let idle: SocketAddr = "127.0.0.1:1531".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", draining, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", idle, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-3", "127.0.0.1:1532".parse().unwrap(), None, None, None));
 
-impl BackendList {
-  pub fn new() -> BackendList {
-    BackendList {
-      backends:       Vec::new(),
-      next_id:        0,
-      load_balancing: Box::new(RandomAlgorithm{}),
+    for _ in 0..2 {
+      backend_map.backends[app_id].backends[0].borrow_mut().inc_connections();
     }
-  }
 
-  pub fn import_configuration_state(backend_vec: &Vec<proxy::Backend>) -> BackendList {
-    let mut list = BackendList::new();
-    for ref backend in backend_vec {
-      let backend = Backend::new(&backend.backend_id, backend.address, backend.sticky_id.clone(), backend.load_balancing_parameters.clone(), backend.backup);
-      list.add_backend(backend);
+    backend_map.remove_backend_graceful(app_id, &draining);
+    backend_map.remove_backend_graceful(app_id, &idle);
+    assert!(backend_map.backends[app_id].has_backend(&draining));
+    assert!(!backend_map.backends[app_id].has_backend(&idle));
+
+    for _ in 0..10 {
+      let backend = backend_map.select_backend(app_id).unwrap();
+      assert_eq!("myapp-3", backend.borrow().backend_id);
     }
 
-    list
+    backend_map.close_backend_connection(app_id, &draining);
+    assert!(backend_map.backends[app_id].has_backend(&draining));
+    backend_map.close_backend_connection(app_id, &draining);
+    assert!(!backend_map.backends[app_id].has_backend(&draining));
+    assert_eq!(1, backend_map.backends[app_id].backends.len());
   }
 
-  pub fn add_backend(&mut self, backend: Backend) {
-    if self.backends.iter().find(|b| (*b.borrow()).address == backend.address).is_none() {
-      let backend = Rc::new(RefCell::new(backend));
-      self.backends.push(backend);
-      self.next_id += 1;
-    }
-  }
+  #[test]
+  fn it_should_remove_a_drained_backend_released_by_a_session() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let draining: SocketAddr = "127.0.0.1:1533".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", draining, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1534".parse().unwrap(), None, None, None));
 
-  pub fn remove_backend(&mut self, backend_address: &SocketAddr) {
-    self.backends.retain(|backend| &(*backend.borrow()).address != backend_address);
-  }
+    let backend = backend_map.backends[app_id].backends[0].clone();
+    backend.borrow_mut().inc_connections();
+    backend_map.remove_backend_graceful(app_id, &draining);
+    assert!(backend_map.backends[app_id].has_backend(&draining));
 
-  pub fn has_backend(&self, backend_address: &SocketAddr) -> bool {
-    self.backends.iter().any(|backend| &(*backend.borrow()).address == backend_address)
+    // like the sessions' remove_backend, the connection is released on the backend
+    backend.borrow_mut().dec_connections();
+    assert_eq!(BackendStatus::Closed, backend.borrow().status);
+
+    let selected = backend_map.select_backend(app_id).unwrap();
+    assert_eq!("myapp-2", selected.borrow().backend_id);
+    assert!(!backend_map.backends[app_id].has_backend(&draining));
+    assert_eq!(1, backend_map.backends[app_id].backends.len());
   }
 
-  pub fn find_backend(&mut self, backend_address: &SocketAddr) -> Option<&mut Rc<RefCell<Backend>>> {
-    self.backends.iter_mut().find(|backend| &(*backend.borrow()).address == backend_address)
+  #[test]
+  fn it_should_reset_the_uptime_when_a_backend_comes_back() {
+    let mut backends_list = BackendList::new();
+    let address: SocketAddr = "127.0.0.1:1540".parse().unwrap();
+    backends_list.add_backend(Backend::new("myback-1", address, None, None, None));
+    assert!(backends_list.backends[0].borrow().available_since.is_some());
+
+    let start = Instant::now();
+    backends_list.set_health(&address, false, start);
+    assert_eq!(None, backends_list.backends[0].borrow().uptime_at(start));
+
+    let back_up = start + Duration::from_secs(10);
+    backends_list.set_health(&address, true, back_up);
+    backends_list.set_health(&address, true, back_up + Duration::from_secs(1));
+    assert_eq!(Some(Duration::from_secs(5)), backends_list.backends[0].borrow().uptime_at(back_up + Duration::from_secs(5)));
+
+    let down_again = back_up + Duration::from_secs(20);
+    backends_list.set_health(&address, false, down_again);
+    backends_list.set_health(&address, true, down_again + Duration::from_secs(1));
+    assert_eq!(Some(Duration::from_secs(2)), backends_list.backends[0].borrow().uptime_at(down_again + Duration::from_secs(3)));
   }
 
-  pub fn find_sticky(&mut self, sticky_session: &str) -> Option<&mut Rc<RefCell<Backend>>> {
-    self.backends.iter_mut()
-      .find(|b| b.borrow().sticky_id.as_ref().map(|s| s.as_str()) == Some(sticky_session) )
-      .and_then(|b| {
-        if b.borrow().can_open() {
-          Some(b)
-        } else {
-          None
-        }
-      })
+  #[test]
+  fn it_should_find_backends_by_backend_id() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1550".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1551".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1552".parse().unwrap(), None, None, None));
+    assert_eq!(2, backend_map.total_count(app_id));
+
+    let backend = backend_map.backend_by_backend_id(app_id, "myapp-1").unwrap();
+    assert_eq!("127.0.0.1:1550".parse::<SocketAddr>().unwrap(), backend.borrow().address);
+
+    assert!(backend_map.backend_by_backend_id(app_id, "myapp-3").is_none());
+    assert!(backend_map.backend_by_backend_id("unknown", "myapp-1").is_none());
+
+    // the backend reusing the id was refused
+    let backend = backend_map.backend_by_backend_id(app_id, "myapp-2").unwrap();
+    assert_eq!("127.0.0.1:1551".parse::<SocketAddr>().unwrap(), backend.borrow().address);
   }
 
-  pub fn available_backends(&mut self, backup: bool) -> Vec<Rc<RefCell<Backend>>> {
-    self.backends.iter()
-      .filter(|backend| (*backend.borrow()).backup == backup && (*backend.borrow()).can_open())
-      .map(|backend| (*backend).clone())
-      .collect()
+  #[test]
+  fn it_should_count_available_and_total_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    for i in 0..4 {
+      let address = format!("127.0.0.1:{}", 1560 + i).parse().unwrap();
+      backend_map.add_backend(app_id, Backend::new(&format!("myapp-{}", i), address, None, None, None));
+    }
+    backend_map.report_health(app_id, &"127.0.0.1:1561".parse().unwrap(), false);
+    backend_map.backends[app_id].backends[2].borrow_mut().set_closing();
+
+    assert_eq!(2, backend_map.available_count(app_id));
+    assert_eq!(4, backend_map.total_count(app_id));
+    assert_eq!(0, backend_map.available_count("unknown"));
+    assert_eq!(0, backend_map.total_count("unknown"));
   }
 
-  pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
-    let mut backends = self.available_backends(false);
+  #[test]
+  fn it_should_drain_and_remove_a_backend_weighted_to_zero() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1570".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, Some("server-1".to_string()), Some(LoadBalancingParams { weight: 50 }), None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1571".parse().unwrap(), None, Some(LoadBalancingParams { weight: 50 }), None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::WeightedRandom);
 
-    if backends.is_empty() {
-      backends = self.available_backends(true);
-    }
+    backend_map.backend_from_sticky_session(app_id, "server-1").unwrap();
+    backend_map.set_backend_weight(app_id, &address, 0);
 
-    if backends.is_empty() {
-      None
-    } else {
-      self.load_balancing.next_available_backend(&backends)
+    for _ in 0..20 {
+      let backend = backend_map.select_backend(app_id).unwrap();
+      assert_eq!("myapp-2", backend.borrow().backend_id);
     }
+
+    let (backend, _) = backend_map.backend_from_sticky_session(app_id, "server-1").unwrap();
+    assert_eq!("myapp-1", backend.borrow().backend_id);
+    assert_eq!(0, backend.borrow().weight());
+
+    backend_map.close_backend_connection(app_id, &address);
+    assert!(backend_map.backends[app_id].has_backend(&address));
+    backend_map.close_backend_connection(app_id, &address);
+    assert!(!backend_map.backends[app_id].has_backend(&address));
   }
 
-  pub fn set_load_balancing_policy(&mut self, load_balancing_policy: LoadBalancingAlgorithms) {
-    match load_balancing_policy {
-      LoadBalancingAlgorithms::RoundRobin => self.load_balancing = Box::new(RoundRobinAlgorithm{ next_backend: 0 }),
-      LoadBalancingAlgorithms::Random => self.load_balancing = Box::new(RandomAlgorithm{}),
-      LoadBalancingAlgorithms::LeastConnections => self.load_balancing = Box::new(LeastConnectionsAlgorithm{}),
-    }
+  #[test]
+  fn it_should_buffer_a_weight_change_until_the_transaction_is_committed() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let weighted: SocketAddr = "127.0.0.1:1572".parse().unwrap();
+    let drained: SocketAddr = "127.0.0.1:1573".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", weighted, None, Some(LoadBalancingParams { weight: 50 }), None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", drained, None, Some(LoadBalancingParams { weight: 50 }), None));
+
+    backend_map.begin_transaction(app_id);
+    backend_map.set_backend_weight(app_id, &weighted, 20);
+    backend_map.set_backend_weight(app_id, &drained, 0);
+
+    let weights: Vec<u8> = backend_map.backends[app_id].backends.iter().map(|b| b.borrow().weight()).collect();
+    assert_eq!(vec![50, 50], weights);
+
+    backend_map.commit_transaction(app_id);
+    assert_eq!(1, backend_map.backends[app_id].backends.len());
+    assert_eq!(20, backend_map.backends[app_id].backends[0].borrow().weight());
   }
-}
 
-#[cfg(test)]
-mod backends_test {
+  #[test]
+  fn it_should_repeat_selections_with_a_seeded_rng() {
+    let selections = |algorithm: Box<LoadBalancingAlgorithm>| -> Vec<usize> {
+      let mut backend_map = BackendMap::new();
+      for i in 0..5 {
+        let address = format!("127.0.0.1:{}", 1580 + i).parse().unwrap();
+        let weight = if i == 0 { 60 } else { 10 };
+        backend_map.add_backend("myapp", Backend::new(&format!("myapp-{}", i), address, None, Some(LoadBalancingParams { weight }), None));
+      }
+      backend_map.set_load_balancing_algorithm_for_app("myapp", algorithm);
 
-  use super::*;
-  use std::{thread,sync::mpsc::*,net::TcpListener};
+      (0..10).map(|_| {
+        let backend = backend_map.select_backend("myapp").unwrap();
+        let port = backend.borrow().address.port();
+        port as usize - 1580
+      }).collect()
+    };
 
+    assert_eq!(vec![2, 0, 4, 2, 4, 3, 4, 3, 2, 2],
+      selections(Box::new(RandomAlgorithm::with_rng(Box::new(StdRng::seed_from_u64(42))))));
+    assert_eq!(vec![0, 4, 0, 0, 0, 0, 0, 3, 0, 2],
+      selections(Box::new(WeightedRandomAlgorithm::with_rng(Box::new(StdRng::seed_from_u64(42))))));
+  }
 
-  fn run_mock_tcp_server(addr: &str, stopper: Receiver<()>) {
-    let mut run = true;
-    let listener = TcpListener::bind(addr).unwrap();
+  #[test]
+  fn it_should_notify_the_observer_once_per_availability_transition() {
+    let events: Rc<RefCell<Vec<(String, AvailabilityEvent)>>> = Rc::new(RefCell::new(Vec::new()));
+    let observed = events.clone();
+    let mut backend_map = BackendMap::with_observer(move |app_id, event| {
+      observed.borrow_mut().push((app_id.to_string(), event));
+    });
 
-    thread::spawn(move || {
-      while run {
-        for stream in listener.incoming() {
-          // accept connections
-          if let Ok(()) = stopper.try_recv() {
-            run = false;
-          }
-        }
+    let app_id = "myapp";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
 
-      }
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
+    assert!(events.borrow().is_empty());
+
+    backend_map.report_health(app_id, &address, false);
+    assert!(backend_map.backend_from_app_id(app_id).is_err());
+    assert!(backend_map.backend_from_app_id(app_id).is_err());
+    assert_eq!(vec![(app_id.to_string(), AvailabilityEvent::Lost)], *events.borrow());
+
+    backend_map.report_health(app_id, &address, true);
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
+    assert!(backend_map.backend_from_app_id(app_id).is_ok());
+    assert_eq!(vec![
+      (app_id.to_string(), AvailabilityEvent::Lost),
+      (app_id.to_string(), AvailabilityEvent::Recovered),
+    ], *events.borrow());
+  }
+
+  #[test]
+  fn it_should_mark_the_app_unavailable_after_the_last_failed_attempt() {
+    let events: Rc<RefCell<Vec<(String, AvailabilityEvent)>>> = Rc::new(RefCell::new(Vec::new()));
+    let observed = events.clone();
+    let mut backend_map = BackendMap::with_observer(move |app_id, event| {
+      observed.borrow_mut().push((app_id.to_string(), event));
     });
+
+    let app_id = "myapp";
+    for port in 80..83 {
+      let address = format!("255.255.255.255:{}", port).parse().unwrap();
+      backend_map.add_backend(app_id, Backend::new(&format!("myapp-{}", port), address, None, None, None));
+    }
+    assert_eq!(3, backend_map.max_failures);
+
+    assert_eq!(Some(ConnectionError::NoBackendAvailable), backend_map.backend_from_app_id(app_id).err());
+    assert!(!backend_map.available);
+    assert!(backend_map.unavailable_apps.contains(app_id));
+    assert_eq!(vec![(app_id.to_string(), AvailabilityEvent::Lost)], *events.borrow());
   }
 
   #[test]
-  fn it_should_retrieve_a_backend_from_app_id_when_backends_have_been_recorded() {
+  fn it_should_move_a_backend_to_its_new_address() {
     let mut backend_map = BackendMap::new();
     let app_id = "myapp";
+    let old_address: SocketAddr = "127.0.0.1:1500".parse().unwrap();
+    let new_address: SocketAddr = "127.0.0.1:1501".parse().unwrap();
 
-    let backend_addr = "127.0.0.1:1236";
-    let (sender, receiver) = channel();
-    run_mock_tcp_server(backend_addr, receiver);
+    backend_map.add_backend(app_id, Backend::new("myapp-1", old_address, None, None, None));
+    backend_map.backends[app_id].backends[0].borrow_mut().failures = 3;
 
-    backend_map.add_backend(app_id, Backend::new(&format!("{}-1", app_id), backend_addr.parse().unwrap(), None, None, None));
+    backend_map.upsert_backend(app_id, Backend::new("myapp-1", new_address, None, None, None));
 
-    assert!(backend_map.backend_from_app_id(app_id).is_ok());
-    sender.send(()).unwrap();
+    let app_backends = backend_map.backends.get_mut(app_id).unwrap();
+    assert_eq!(1, app_backends.backends.len());
+    assert!(!app_backends.has_backend(&old_address));
+    assert_eq!(0, app_backends.backends[0].borrow().failures);
+    for _ in 0..5 {
+      assert_eq!(new_address, app_backends.next_available_backend().unwrap().borrow().address);
+    }
   }
 
   #[test]
-  fn it_should_not_retrieve_a_backend_from_app_id_when_backend_has_not_been_recorded() {
+  fn it_should_drain_a_moved_backend_when_its_new_address_is_taken() {
     let mut backend_map = BackendMap::new();
-    let app_not_recorded = "not";
-    backend_map.add_backend("foo", Backend::new("foo-1", "127.0.0.1:9001".parse().unwrap(), None, None, None));
+    let app_id = "myapp";
+    let old_address: SocketAddr = "127.0.0.1:1504".parse().unwrap();
+    let taken_address: SocketAddr = "127.0.0.1:1505".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", old_address, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", taken_address, None, None, None));
 
-    assert!(backend_map.backend_from_app_id(app_not_recorded).is_err());
+    let stale = backend_map.backends[app_id].backends[0].clone();
+    stale.borrow_mut().inc_connections();
+    backend_map.upsert_backend(app_id, Backend::new("myapp-1", taken_address, None, None, None));
+
+    assert_eq!(BackendStatus::Closing, stale.borrow().status);
+    assert!(backend_map.backends[app_id].has_backend(&old_address));
+    for _ in 0..5 {
+      assert_eq!(taken_address, backend_map.select_backend(app_id).unwrap().borrow().address);
+    }
+
+    backend_map.close_backend_connection(app_id, &old_address);
+    assert!(!backend_map.backends[app_id].has_backend(&old_address));
+    assert_eq!(1, backend_map.total_count(app_id));
   }
 
   #[test]
-  fn it_should_not_retrieve_a_backend_from_app_id_when_backend_list_is_empty() {
+  fn it_should_add_a_backend_with_an_unknown_backend_id() {
     let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
 
-    assert!(backend_map.backend_from_app_id("dumb").is_err());
+    backend_map.upsert_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1502".parse().unwrap(), None, None, None));
+    backend_map.upsert_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1503".parse().unwrap(), None, None, None));
+    backend_map.upsert_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1503".parse().unwrap(), None, None, None));
+
+    assert_eq!(2, backend_map.total_count(app_id));
+    assert!(backend_map.backend_by_backend_id(app_id, "myapp-2").is_some());
   }
 
   #[test]
-  fn it_should_retrieve_a_backend_from_sticky_session_when_the_backend_has_been_recorded() {
+  fn it_should_keep_the_recent_selections_in_order() {
     let mut backend_map = BackendMap::new();
     let app_id = "myapp";
-    let sticky_session = "server-2";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener2 = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let address2 = listener2.local_addr().unwrap();
 
-    let backend_addr = "127.0.0.1:3456";
-    let (sender, receiver) = channel();
-    run_mock_tcp_server(backend_addr, receiver);
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, Some("sticky-1".to_string()), None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", address2, None, None, None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::RoundRobin);
 
-    backend_map.add_backend(app_id, Backend::new(&format!("{}-1", app_id), "127.0.0.1:9001".parse().unwrap(), Some("server-1".to_string()), None, None));
-    backend_map.add_backend(app_id, Backend::new(&format!("{}-2", app_id), "127.0.0.1:9000".parse().unwrap(), Some("server-2".to_string()), None, None));
-    // sticky backend
-    backend_map.add_backend(app_id, Backend::new(&format!("{}-3", app_id), backend_addr.parse().unwrap(), Some("server-3".to_string()), None, None));
+    backend_map.backend_from_app_id(app_id).unwrap();
+    backend_map.backend_from_app_id(app_id).unwrap();
+    backend_map.backend_from_sticky_session(app_id, "sticky-1").unwrap();
 
-    assert!(backend_map.backend_from_sticky_session(app_id, sticky_session).is_ok());
-    sender.send(()).unwrap();
+    let recent: Vec<(SocketAddr, bool)> = backend_map.recent_selections(app_id).iter()
+      .map(|selection| (selection.address, selection.sticky)).collect();
+    assert_eq!(vec![(address, false), (address2, false), (address, true)], recent);
+
+    for _ in 0..RECENT_SELECTIONS_CAPACITY {
+      backend_map.backends.get_mut(app_id).unwrap().record_recent_selection(address2, false);
+    }
+    let recent = backend_map.recent_selections(app_id);
+    assert_eq!(RECENT_SELECTIONS_CAPACITY, recent.len());
+    assert!(recent.iter().all(|selection| selection.address == address2));
   }
 
   #[test]
-  fn it_should_not_retrieve_a_backend_from_sticky_session_when_the_backend_has_not_been_recorded() {
+  fn it_should_connect_to_the_forced_backend_once() {
     let mut backend_map = BackendMap::new();
     let app_id = "myapp";
-    let sticky_session = "test";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener2 = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let forced_address = listener2.local_addr().unwrap();
 
-    assert!(backend_map.backend_from_sticky_session(app_id, sticky_session).is_err());
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", forced_address, None, None, None));
+    backend_map.backends[app_id].backends[1].borrow_mut().active_connections = 10;
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::LeastConnections);
+
+    backend_map.force_next_selection(forced_address);
+    let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!(forced_address, backend.borrow().address);
+
+    let (backend, _) = backend_map.backend_from_app_id(app_id).unwrap();
+    assert_eq!(address, backend.borrow().address);
   }
 
   #[test]
-  fn it_should_not_retrieve_a_backend_from_sticky_session_when_the_backend_list_is_empty() {
+  fn it_should_pick_the_first_usable_backend_in_configuration_order() {
+    let mut backends_list = BackendList::with_policy(LoadBalancingAlgorithms::ConfigOrder);
+    for i in 0..3 {
+      let address: SocketAddr = format!("127.0.0.1:{}", 1510 + i).parse().unwrap();
+      backends_list.add_backend(Backend::new(&format!("myapp-{}", i), address, None, None, None));
+    }
+
+    for _ in 0..5 {
+      assert_eq!("myapp-0", backends_list.next_available_backend().unwrap().borrow().backend_id);
+    }
+
+    backends_list.backends[0].borrow_mut().healthy = Some(false);
+    for _ in 0..5 {
+      assert_eq!("myapp-1", backends_list.next_available_backend().unwrap().borrow().backend_id);
+    }
+
+    backends_list.backends[0].borrow_mut().healthy = None;
+    assert_eq!("myapp-0", backends_list.next_available_backend().unwrap().borrow().backend_id);
+  }
+
+  #[test]
+  fn it_should_reconcile_a_leaked_connection_counter() {
     let mut backend_map = BackendMap::new();
-    let myapp_not_recorded = "myapp";
-    let sticky_session = "test";
+    let app_id = "myapp";
+    let address: SocketAddr = "127.0.0.1:1520".parse().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, None, None, None));
 
-    assert!(backend_map.backend_from_sticky_session(myapp_not_recorded, sticky_session).is_err());
+    for _ in 0..20 {
+      backend_map.backends[app_id].backends[0].borrow_mut().inc_connections();
+    }
+    backend_map.close_backend_connection(app_id, &address);
+
+    backend_map.reconcile_connections(app_id, &address, 4);
+    {
+      let backend = backend_map.backends[app_id].backends[0].borrow();
+      assert_eq!(4, backend.active_connections);
+      assert_eq!(backend.opened_connections - backend.closed_connections, backend.active_connections);
+    }
+
+    backend_map.remove_backend_graceful(app_id, &address);
+    backend_map.reconcile_connections(app_id, &address, 0);
+    assert_eq!(0, backend_map.total_count(app_id));
   }
 
   #[test]
-  fn it_should_add_a_backend_when_he_doesnt_already_exist() {
-    let backend_id = "myback";
-    let mut backends_list = BackendList::new();
-    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+  fn it_should_report_how_the_backend_was_selected() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    backend_map.add_backend(app_id, Backend::new("myapp-1", address, Some("sticky-1".to_string()), None, None));
+    backend_map.set_load_balancing_policy_for_app(app_id, LoadBalancingAlgorithms::RoundRobin);
 
-    assert_eq!(1, backends_list.backends.len());
+    let (backend, _, kind) = backend_map.backend_with_selection_kind(app_id, Some("sticky-1")).unwrap();
+    assert_eq!(address, backend.borrow().address);
+    assert_eq!(SelectionKind::StickyHit, kind);
+
+    let (_, _, kind) = backend_map.backend_with_selection_kind(app_id, Some("unknown")).unwrap();
+    assert_eq!(SelectionKind::StickyFallback("roundrobin"), kind);
+
+    let (_, _, kind) = backend_map.backend_with_selection_kind(app_id, None).unwrap();
+    assert_eq!(SelectionKind::Fresh("roundrobin"), kind);
   }
 
+  #[cfg(debug_assertions)]
   #[test]
-  fn it_should_not_add_a_backend_when_he_already_exist() {
-    let backend_id = "myback";
+  fn it_should_detect_duplicate_addresses_when_validating() {
     let mut backends_list = BackendList::new();
-    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:80".parse().unwrap(), None, None, None));
+    assert!(backends_list.validate().is_ok());
 
-    //same backend id
-    backends_list.add_backend(Backend::new(backend_id, "127.0.0.1:80".parse().unwrap(), None, None, None));
+    // bypass add_backend, which would refuse the duplicates
+    let duplicate = Backend::new("myback-2", "127.0.0.1:80".parse().unwrap(), None, None, None);
+    backends_list.backends.push(Rc::new(RefCell::new(duplicate)));
+    assert!(backends_list.validate().unwrap_err().contains("duplicate backend address"));
+    backends_list.backends.pop();
 
-    assert_eq!(1, backends_list.backends.len());
+    let duplicate = Backend::new("myback-1", "127.0.0.1:81".parse().unwrap(), None, None, None);
+    backends_list.backends.push(Rc::new(RefCell::new(duplicate)));
+    assert!(backends_list.validate().unwrap_err().contains("duplicate backend id"));
+    backends_list.backends[0].borrow_mut().set_closing();
+    assert!(backends_list.validate().is_ok());
+    backends_list.backends.pop();
+
+    backends_list.backends[0].borrow_mut().rebalanced_weight = Some(10);
+    assert!(backends_list.validate().unwrap_err().contains("rebalanced weight"));
+    backends_list.load_balancing = load_balancing_algorithm(LoadBalancingAlgorithms::WeightedRandom);
+    assert!(backends_list.validate().is_ok());
   }
 }
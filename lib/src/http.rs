@@ -16,7 +16,7 @@ use mio_extras::timer::{Timer, Timeout};
 
 use sozu_command::scm_socket::{Listeners,ScmSocket};
 use sozu_command::proxy::{Application,ProxyRequestData,HttpFront,HttpListener,
-  ProxyRequest,ProxyResponse,ProxyResponseStatus,ProxyEvent};
+  ProxyRequest,ProxyResponse,ProxyResponseStatus};
 use sozu_command::logging;
 use sozu_command::buffer::Buffer;
 
@@ -29,9 +29,8 @@ use super::protocol::{ProtocolResult,StickySession,Http,Pipe};
 use super::protocol::http::DefaultAnswerStatus;
 use super::protocol::proxy_protocol::expect::ExpectProxyProtocol;
 use super::server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,
-  ListenSession, CONN_RETRIES, push_event};
+  ListenSession, CONN_RETRIES};
 use super::socket::server_bind;
-use super::retry::RetryPolicy;
 use super::protocol::http::parser::{hostname_and_port, RequestState};
 use super::trie::TrieNode;
 use util::UnwrapLog;
@@ -54,6 +53,7 @@ pub struct Session {
   back_connected:     BackendConnectionStatus,
   protocol:           Option<State>,
   pool:               Weak<RefCell<Pool<Buffer>>>,
+  backends:           Weak<RefCell<BackendMap>>,
   metrics:            SessionMetrics,
   pub app_id:         Option<String>,
   sticky_name:        String,
@@ -64,7 +64,7 @@ pub struct Session {
 }
 
 impl Session {
-  pub fn new(sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>,
+  pub fn new(sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>, backends: Weak<RefCell<BackendMap>>,
     public_address: Option<SocketAddr>, expect_proxy: bool, sticky_name: String, timeout: Timeout,
     listen_token: Token) -> Option<Session> {
     let request_id = Uuid::new_v4().to_hyphenated();
@@ -85,6 +85,7 @@ impl Session {
         protocol:           Some(pr),
         frontend_token:     token,
         pool,
+        backends,
         metrics:            SessionMetrics::new(),
         app_id:             None,
         sticky_name,
@@ -327,15 +328,15 @@ impl Session {
   }
 
   fn set_back_connected(&mut self, connected: BackendConnectionStatus) {
+    let last = self.back_connected;
     self.back_connected = connected;
+    // confirmed connections are resolved by poll_backend_connection
+    if last == BackendConnectionStatus::Connecting && connected == BackendConnectionStatus::NotConnected {
+      self.backends.upgrade().map(|backends| backends.borrow_mut().connection_attempt_resolved());
+    }
+
     if connected == BackendConnectionStatus::Connected {
       gauge_add!("backend.connections", 1);
-      self.backend.as_ref().map(|backend| {
-        let ref mut backend = *backend.borrow_mut();
-        //successful connection, rest failure counter
-        backend.failures = 0;
-        backend.retry_policy.succeed();
-      });
     }
   }
 
@@ -351,6 +352,9 @@ impl Session {
     if let Some(backend) = self.backend.take() {
       self.http().map(|h| h.clear_back_token());
 
+      if self.back_connected == BackendConnectionStatus::Connecting {
+        (*backend.borrow_mut()).connection_abandoned();
+      }
       (*backend.borrow_mut()).dec_connections();
     }
   }
@@ -371,21 +375,25 @@ impl Session {
     }
   }
 
-  fn fail_backend_connection(&mut self) {
-    self.backend.as_ref().map(|backend| {
-      let ref mut backend = *backend.borrow_mut();
-      backend.failures += 1;
+  /// checks the pending connection to the backend, once its socket got an event
+  fn poll_backend_connection(&self) -> Result<BackendConnectionStatus, ConnectionError> {
+    let backend = self.backend.as_ref().ok_or(ConnectionError::NoBackendAvailable)?;
+    let socket = self.back_socket().ok_or(ConnectionError::NoBackendAvailable)?;
 
-      let already_unavailable = backend.retry_policy.is_down();
-      backend.retry_policy.fail();
-      incr!("backend.connections.error");
-      if !already_unavailable && backend.retry_policy.is_down() {
-        error!("backend server {} at {} is down", backend.backend_id, backend.address);
-        incr!("backend.down");
+    match self.backends.upgrade() {
+      Some(backends) => backends.borrow_mut().poll_backend_connection(backend, socket),
+      None           => backend.borrow_mut().poll_connect(socket),
+    }
+  }
 
-        push_event(ProxyEvent::BackendDown(backend.backend_id.clone(), backend.address));
+  /// counts a failed connection to the backend that poll_connect did not see
+  fn backend_connection_failed(&self) {
+    if let Some(ref backend) = self.backend {
+      match self.backends.upgrade() {
+        Some(backends) => backends.borrow_mut().backend_connection_failed(backend),
+        None           => backend.borrow_mut().connection_failed(),
       }
-    });
+    }
   }
 
   fn reset_connection_attempt(&mut self) {
@@ -503,18 +511,33 @@ impl ProxySession for Session {
     self.metrics().service_start();
 
     if self.back_connected() == BackendConnectionStatus::Connecting {
-      if self.back_readiness().map(|r| r.event.is_hup()).unwrap_or(false) {
-        //retry connecting the backend
-        error!("{} error connecting to backend, trying again", self.log_context());
-        self.metrics().service_stop();
-        self.connection_attempt += 1;
-        self.fail_backend_connection();
-
-        let backend_token = self.back_token();
-        return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
-      } else if self.back_readiness().map(|r| r.event != UnixReady::from(Ready::empty())).unwrap_or(false) {
-        self.reset_connection_attempt();
-        self.set_back_connected(BackendConnectionStatus::Connected);
+      if self.back_readiness().map(|r| r.event != UnixReady::from(Ready::empty())).unwrap_or(false) {
+        let hup = self.back_readiness().map(|r| r.event.is_hup()).unwrap_or(false);
+        match self.poll_backend_connection() {
+          Ok(BackendConnectionStatus::Connected) => {
+            self.reset_connection_attempt();
+            self.set_back_connected(BackendConnectionStatus::Connected);
+          },
+          Ok(BackendConnectionStatus::Connecting) if !hup => {
+            // the event did not complete the connection, wait for the next one
+            self.back_readiness().map(|r| r.event = UnixReady::from(Ready::empty()));
+          },
+          res => {
+            // a hang up before the connection completed leaves no socket
+            // error for poll_connect to see, the failure is counted here
+            if res.is_ok() {
+              self.backend_connection_failed();
+            }
+
+            //retry connecting the backend
+            error!("{} error connecting to backend, trying again", self.log_context());
+            self.metrics().service_stop();
+            self.connection_attempt += 1;
+
+            let backend_token = self.back_token();
+            return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
+          },
+        }
       }
     }
 
@@ -773,8 +796,7 @@ impl Proxy {
           let sticky_name =  self.listeners[&session.listen_token].config.sticky_name.clone();
           session.http().map(|http| {
             http.sticky_session =
-              Some(StickySession::new(backend.borrow().sticky_id.clone().unwrap_or_else(|| {
-                backend.borrow().backend_id.clone()})));
+              Some(StickySession::new(backend.borrow().sticky_session_id().to_string()));
             http.sticky_name = sticky_name;
           });
         }
@@ -1198,7 +1220,7 @@ impl ProxyConfiguration<Session> for Proxy {
       if let Err(e) = frontend_sock.set_nodelay(true) {
         error!("error setting nodelay on front socket({:?}): {:?}", frontend_sock, e);
       }
-      if let Some(c) = Session::new(frontend_sock, session_token, Rc::downgrade(&self.pool),
+      if let Some(c) = Session::new(frontend_sock, session_token, Rc::downgrade(&self.pool), Rc::downgrade(&self.backends),
       listener.config.public_address, listener.config.expect_proxy, listener.config.sticky_name.clone(), timeout,
       listener.token) {
         if let Err(e) = poll.register(
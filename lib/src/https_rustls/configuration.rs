@@ -362,7 +362,7 @@ impl Proxy {
           let sticky_name = self.listeners[&session.listen_token].config.sticky_name.clone();
           session.http().map(|http| {
             http.sticky_session =
-              Some(StickySession::new(backend.borrow().sticky_id.clone().unwrap_or(backend.borrow().backend_id.clone())));
+              Some(StickySession::new(backend.borrow().sticky_session_id().to_string()));
             http.sticky_name = sticky_name;
           });
         }
@@ -472,7 +472,8 @@ impl ProxyConfiguration<Session> for Proxy {
         }
 
         let session = ServerSession::new(&listener.ssl_config);
-        let c = Session::new(session, frontend_sock, session_token, Rc::downgrade(&self.pool), listener.config.public_address,
+        let c = Session::new(session, frontend_sock, session_token, Rc::downgrade(&self.pool), Rc::downgrade(&self.backends),
+          listener.config.public_address,
           listener.config.expect_proxy, listener.config.sticky_name.clone(), timeout, Token(token.0));
 
         Ok((Rc::new(RefCell::new(c)), false))
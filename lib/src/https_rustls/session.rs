@@ -10,21 +10,19 @@ use uuid::Uuid;
 use rustls::{ServerSession,Session as ClientSession,ProtocolVersion,SupportedCipherSuite,CipherSuite};
 use mio_extras::timer::{Timer, Timeout};
 use sozu_command::buffer::Buffer;
-use sozu_command::proxy::ProxyEvent;
 
 use protocol::http::parser::RequestState;
 use pool::Pool;
-use {Backend,SessionResult,Protocol,Readiness,SessionMetrics, ProxySession,
+use {Backend,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics, ProxySession,
   BackendConnectionStatus, CloseResult};
 use socket::FrontRustls;
 use protocol::{ProtocolResult,Http,Pipe};
 use protocol::rustls::TlsHandshake;
 use protocol::http::DefaultAnswerStatus;
 use protocol::proxy_protocol::expect::ExpectProxyProtocol;
-use retry::RetryPolicy;
 use util::UnwrapLog;
 use buffer_queue::BufferQueue;
-use server::push_event;
+use backends::BackendMap;
 
 pub enum State {
   Expect(ExpectProxyProtocol<TcpStream>, ServerSession),
@@ -40,6 +38,7 @@ pub struct Session {
   protocol:           Option<State>,
   pub public_address: Option<SocketAddr>,
   pool:               Weak<RefCell<Pool<Buffer>>>,
+  backends:           Weak<RefCell<BackendMap>>,
   pub metrics:        SessionMetrics,
   pub app_id:         Option<String>,
   sticky_name:        String,
@@ -52,7 +51,7 @@ pub struct Session {
 
 impl Session {
   pub fn new(ssl: ServerSession, sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>,
-    public_address: Option<SocketAddr>, expect_proxy: bool, sticky_name: String, timeout: Timeout, listen_token: Token) -> Session {
+    backends: Weak<RefCell<BackendMap>>, public_address: Option<SocketAddr>, expect_proxy: bool, sticky_name: String, timeout: Timeout, listen_token: Token) -> Session {
     let peer_address = if expect_proxy {
       // Will be defined later once the expect proxy header has been received and parsed
       None
@@ -77,6 +76,7 @@ impl Session {
       protocol:       state,
       public_address,
       pool,
+      backends,
       metrics:        SessionMetrics::new(),
       app_id:         None,
       sticky_name,
@@ -384,15 +384,15 @@ impl Session {
   }
 
   fn set_back_connected(&mut self, connected: BackendConnectionStatus) {
+    let last = self.back_connected;
     self.back_connected = connected;
+    // confirmed connections are resolved by poll_backend_connection
+    if last == BackendConnectionStatus::Connecting && connected == BackendConnectionStatus::NotConnected {
+      self.backends.upgrade().map(|backends| backends.borrow_mut().connection_attempt_resolved());
+    }
 
     if connected == BackendConnectionStatus::Connected {
       gauge_add!("backend.connections", 1);
-      self.backend.as_ref().map(|backend| {
-        let backend = &mut (*backend.borrow_mut());
-        backend.failures = 0;
-        backend.retry_policy.succeed();
-      });
     }
   }
 
@@ -404,6 +404,9 @@ impl Session {
     if let Some(backend) = self.backend.take() {
       self.http().map(|h| h.clear_back_token());
 
+      if self.back_connected == BackendConnectionStatus::Connecting {
+        (*backend.borrow_mut()).connection_abandoned();
+      }
       (*backend.borrow_mut()).dec_connections();
     }
   }
@@ -425,21 +428,25 @@ impl Session {
     }
   }
 
-  fn fail_backend_connection(&mut self) {
-    self.backend.as_ref().map(|backend| {
-      let ref mut backend = *backend.borrow_mut();
-      backend.failures += 1;
+  /// checks the pending connection to the backend, once its socket got an event
+  fn poll_backend_connection(&self) -> Result<BackendConnectionStatus, ConnectionError> {
+    let backend = self.backend.as_ref().ok_or(ConnectionError::NoBackendAvailable)?;
+    let socket = self.back_socket().ok_or(ConnectionError::NoBackendAvailable)?;
 
-      let already_unavailable = backend.retry_policy.is_down();
-      backend.retry_policy.fail();
-      incr!("backend.connections.error");
-      if !already_unavailable && backend.retry_policy.is_down() {
-        error!("backend server {} at {} is down", backend.backend_id, backend.address);
-        incr!("backend.down");
+    match self.backends.upgrade() {
+      Some(backends) => backends.borrow_mut().poll_backend_connection(backend, socket),
+      None           => backend.borrow_mut().poll_connect(socket),
+    }
+  }
 
-        push_event(ProxyEvent::BackendDown(backend.backend_id.clone(), backend.address));
+  /// counts a failed connection to the backend that poll_connect did not see
+  fn backend_connection_failed(&self) {
+    if let Some(ref backend) = self.backend {
+      match self.backends.upgrade() {
+        Some(backends) => backends.borrow_mut().backend_connection_failed(backend),
+        None           => backend.borrow_mut().connection_failed(),
       }
-    });
+    }
   }
 
   fn reset_connection_attempt(&mut self) {
@@ -560,18 +567,33 @@ impl ProxySession for Session {
     self.metrics().service_start();
 
     if self.back_connected() == BackendConnectionStatus::Connecting {
-      if self.back_readiness().map(|r| r.event.is_hup()).unwrap_or(false) {
-        //retry connecting the backend
-        error!("{} error connecting to backend, trying again", self.log_context());
-        self.metrics().service_stop();
-        self.connection_attempt += 1;
-        self.fail_backend_connection();
-
-        let backend_token = self.back_token();
-        return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
-      } else if self.back_readiness().map(|r| r.event != UnixReady::from(Ready::empty())).unwrap_or(false) {
-        self.reset_connection_attempt();
-        self.set_back_connected(BackendConnectionStatus::Connected);
+      if self.back_readiness().map(|r| r.event != UnixReady::from(Ready::empty())).unwrap_or(false) {
+        let hup = self.back_readiness().map(|r| r.event.is_hup()).unwrap_or(false);
+        match self.poll_backend_connection() {
+          Ok(BackendConnectionStatus::Connected) => {
+            self.reset_connection_attempt();
+            self.set_back_connected(BackendConnectionStatus::Connected);
+          },
+          Ok(BackendConnectionStatus::Connecting) if !hup => {
+            // the event did not complete the connection, wait for the next one
+            self.back_readiness().map(|r| r.event = UnixReady::from(Ready::empty()));
+          },
+          res => {
+            // a hang up before the connection completed leaves no socket
+            // error for poll_connect to see, the failure is counted here
+            if res.is_ok() {
+              self.backend_connection_failed();
+            }
+
+            //retry connecting the backend
+            error!("{} error connecting to backend, trying again", self.log_context());
+            self.metrics().service_stop();
+            self.connection_attempt += 1;
+
+            let backend_token = self.back_token();
+            return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
+          },
+        }
       }
     }
 
@@ -377,7 +377,14 @@ pub enum ConnectionError {
   HostNotFound,
   NoBackendAvailable,
   ToBeDefined,
-  HttpsRedirect
+  HttpsRedirect,
+  /// the backends are temporarily unavailable, try again after this delay
+  RetryLater(std::time::Duration),
+  TooManyConnections,
+  DeadlineExceeded,
+  /// the application is in the middle of a configuration change
+  Reconfiguring,
+  TooManyRetries,
 }
 
 #[derive(Debug,PartialEq,Eq)]
@@ -393,6 +400,17 @@ pub enum BackendStatus {
   Closed,
 }
 
+/// state of the circuit breaker of a backend
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum CircuitBreakerState {
+  Closed,
+  /// the backend is not used until then, the next connection is a trial
+  Open(std::time::Instant),
+  /// a trial connection is in flight, the other connections are refused
+  /// until poll_connect confirms it or sees it fail
+  HalfOpen,
+}
+
 #[derive(Debug,PartialEq,Eq,Clone)]
 pub struct Backend {
   pub sticky_id:                 Option<String>,
@@ -401,14 +419,36 @@ pub struct Backend {
   pub status:                    BackendStatus,
   pub retry_policy:              retry::RetryPolicyWrapper,
   pub active_connections:        usize,
+  /// connections opened and closed since the backend was added
+  pub opened_connections:        usize,
+  pub closed_connections:        usize,
+  /// the same over the last minute, it shows how much the connections
+  /// to the backend churn
+  pub recent_opened_connections: RollingCounter,
+  pub recent_closed_connections: RollingCounter,
   pub failures:                  usize,
   pub load_balancing_parameters: Option<LoadBalancingParams>,
   pub backup:                    bool,
+  /// health verdict pushed by an external checker, overrides failure detection
+  pub healthy:                   Option<bool>,
+  /// moving averages of the time to establish a connection and of the
+  /// time to answer a request, tracked separately
+  pub connect_time:              Option<std::time::Duration>,
+  pub request_time:              Option<std::time::Duration>,
+  /// the backend changed health too often, it is kept down until then
+  pub flapping_until:            Option<std::time::Instant>,
+  pub circuit_breaker:           CircuitBreakerState,
+  /// when the backend last became available, None while it is down
+  pub available_since:           Option<std::time::Instant>,
+  /// set by BackendMap::rebalance_weights, overrides the configured
+  /// weight until the app's weights are reset
+  pub rebalanced_weight:         Option<u8>,
 }
 
 impl Backend {
   pub fn new(backend_id: &str, address: SocketAddr, sticky_id: Option<String>, load_balancing_parameters: Option<LoadBalancingParams>, backup: Option<bool>) -> Backend {
     let desired_policy = retry::ExponentialBackoffPolicy::new(6);
+    let now = std::time::Instant::now();
     Backend {
       sticky_id,
       backend_id:         backend_id.to_string(),
@@ -416,12 +456,45 @@ impl Backend {
       status:             BackendStatus::Normal,
       retry_policy:       desired_policy.into(),
       active_connections: 0,
+      opened_connections: 0,
+      closed_connections: 0,
+      recent_opened_connections: RollingCounter::new(now),
+      recent_closed_connections: RollingCounter::new(now),
       failures:           0,
       load_balancing_parameters,
       backup: backup.unwrap_or(false),
+      healthy:            None,
+      connect_time:       None,
+      request_time:       None,
+      flapping_until:     None,
+      circuit_breaker:    CircuitBreakerState::Closed,
+      available_since:    Some(now),
+      rebalanced_weight:  None,
     }
   }
 
+  pub fn record_connect_time(&mut self, duration: std::time::Duration) {
+    self.connect_time = Some(moving_average(self.connect_time, duration));
+  }
+
+  pub fn record_request_time(&mut self, duration: std::time::Duration) {
+    self.request_time = Some(moving_average(self.request_time, duration));
+  }
+
+  /// the value stored in the sticky session cookie for this backend:
+  /// the sticky id if one was configured, the backend id otherwise
+  pub fn sticky_session_id(&self) -> &str {
+    self.sticky_id.as_ref().unwrap_or(&self.backend_id)
+  }
+
+  /// load balancing weight: the rebalanced one if any, then the configured
+  /// one. Backends without parameters count as 1
+  pub fn weight(&self) -> u8 {
+    self.rebalanced_weight
+      .or_else(|| self.load_balancing_parameters.as_ref().map(|params| params.weight))
+      .unwrap_or(1)
+  }
+
   pub fn set_closing(&mut self) {
     self.status = BackendStatus::Closing;
   }
@@ -431,16 +504,83 @@ impl Backend {
   }
 
   pub fn can_open(&self) -> bool {
+    self.can_open_at(std::time::Instant::now())
+  }
+
+  pub fn can_open_at(&self, now: std::time::Instant) -> bool {
+    self.status == BackendStatus::Normal && self.is_responsive(now)
+  }
+
+  /// a draining backend still serves the sessions that are stuck to it,
+  /// until its last connection is closed
+  pub fn can_open_sticky_at(&self, now: std::time::Instant) -> bool {
+    self.status != BackendStatus::Closed && self.is_responsive(now)
+  }
+
+  pub fn is_flapping(&self) -> bool {
+    self.flapping_until.map(|until| until > std::time::Instant::now()).unwrap_or(false)
+  }
+
+  /// takes the backend out of the rotation for `cooldown` once it failed
+  /// `max_failures` times in a row: `failures` is reset by a confirmed
+  /// connection. When the cooldown is over, the next connection is a trial:
+  /// it closes the breaker once `poll_connect` confirms it, and reopens it on failure
+  pub fn trip_circuit_breaker(&mut self, max_failures: usize, cooldown: std::time::Duration, now: std::time::Instant) {
+    if self.failures >= max_failures {
+      self.circuit_breaker = CircuitBreakerState::Open(now + cooldown);
+    }
+    self.update_availability(now);
+  }
+
+  /// to be called when a connection is closed before `poll_connect` resolved
+  /// it: if it was the circuit breaker's trial, the next connection is one
+  pub fn connection_abandoned(&mut self) {
+    if self.circuit_breaker == CircuitBreakerState::HalfOpen {
+      self.circuit_breaker = CircuitBreakerState::Open(std::time::Instant::now());
+    }
+  }
+
+  /// tracks when the backend went from down to available
+  pub fn update_availability(&mut self, now: std::time::Instant) {
+    if !self.can_open_at(now) {
+      self.available_since = None;
+    } else if self.available_since.is_none() {
+      self.available_since = Some(now);
+    }
+  }
+
+  /// how long the backend has been continuously available
+  pub fn uptime_at(&self, now: std::time::Instant) -> Option<std::time::Duration> {
+    self.available_since.filter(|since| *since <= now).map(|since| now.duration_since(since))
+  }
+
+  fn is_responsive(&self, now: std::time::Instant) -> bool {
+    match self.circuit_breaker {
+      CircuitBreakerState::Open(until) if now < until => return false,
+      CircuitBreakerState::HalfOpen => return false,
+      _ => {},
+    }
+
+    if self.flapping_until.map(|until| now < until).unwrap_or(false) {
+      return false;
+    }
+
+    if let Some(healthy) = self.healthy {
+      return healthy;
+    }
+
     if let Some(action) = self.retry_policy.can_try() {
-      self.status == BackendStatus::Normal && action == retry::RetryAction::OKAY
+      action == retry::RetryAction::OKAY
     } else {
       false
     }
   }
 
   pub fn inc_connections(&mut self) -> Option<usize> {
-    if self.status == BackendStatus::Normal {
+    if self.status != BackendStatus::Closed {
       self.active_connections += 1;
+      self.opened_connections += 1;
+      self.recent_opened_connections.add(1, std::time::Instant::now());
       Some(self.active_connections)
     } else {
       None
@@ -450,16 +590,12 @@ impl Backend {
   pub fn dec_connections(&mut self) -> Option<usize> {
     match self.status {
       BackendStatus::Normal => {
-        if self.active_connections > 0 {
-          self.active_connections -= 1;
-        }
+        self.connection_closed();
         Some(self.active_connections)
       }
       BackendStatus::Closed  => None,
       BackendStatus::Closing => {
-        if self.active_connections > 0 {
-          self.active_connections -= 1;
-        }
+        self.connection_closed();
         if self.active_connections == 0 {
           self.status = BackendStatus::Closed;
           None
@@ -470,23 +606,163 @@ impl Backend {
     }
   }
 
+  /// replaces the connection counter with the number of connections the
+  /// caller actually holds, the missed opens or closes are counted too
+  pub fn reconcile_connections(&mut self, actual: usize) {
+    let now = std::time::Instant::now();
+    if actual > self.active_connections {
+      self.opened_connections += actual - self.active_connections;
+      self.recent_opened_connections.add(actual - self.active_connections, now);
+    } else {
+      self.closed_connections += self.active_connections - actual;
+      self.recent_closed_connections.add(self.active_connections - actual, now);
+    }
+    self.active_connections = actual;
+  }
+
+  fn connection_closed(&mut self) {
+    if self.active_connections > 0 {
+      self.active_connections -= 1;
+      self.closed_connections += 1;
+      self.recent_closed_connections.add(1, std::time::Instant::now());
+    }
+  }
+
   pub fn try_connect(&mut self) -> Result<mio::tcp::TcpStream, ConnectionError> {
     if self.status != BackendStatus::Normal {
       return Err(ConnectionError::NoBackendAvailable);
     }
 
+    self.connect()
+  }
+
+  /// like `try_connect`, but accepts a draining backend
+  pub fn try_connect_sticky(&mut self) -> Result<mio::tcp::TcpStream, ConnectionError> {
+    if self.status == BackendStatus::Closed {
+      return Err(ConnectionError::NoBackendAvailable);
+    }
+
+    self.connect()
+  }
+
+  fn connect(&mut self) -> Result<mio::tcp::TcpStream, ConnectionError> {
     //FIXME: what happens if the connect() call fails with EINPROGRESS?
     let conn = mio::tcp::TcpStream::connect(&self.address).map_err(|_| ConnectionError::NoBackendAvailable);
     if conn.is_ok() {
       //self.retry_policy.succeed();
       self.inc_connections();
+      if let CircuitBreakerState::Open(_) = self.circuit_breaker {
+        self.circuit_breaker = CircuitBreakerState::HalfOpen;
+      }
     } else {
       self.retry_policy.fail();
       self.failures += 1;
     }
+    self.update_availability(std::time::Instant::now());
 
     conn
   }
+
+  /// checks the progress of a non blocking connection returned by
+  /// `try_connect`. A failure is counted for the backend, the connection
+  /// itself is released by its owner when it closes the socket
+  pub fn poll_connect(&mut self, socket: &mio::tcp::TcpStream) -> Result<BackendConnectionStatus, ConnectionError> {
+    let connected = match socket.take_error() {
+      Ok(None) => match socket.peer_addr() {
+        Ok(_) => Some(true),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotConnected => None,
+        Err(_) => Some(false),
+      },
+      _ => Some(false),
+    };
+
+    match connected {
+      None => Ok(BackendConnectionStatus::Connecting),
+      Some(true) => {
+        self.failures = 0;
+        self.retry_policy.succeed();
+        self.circuit_breaker = CircuitBreakerState::Closed;
+        self.update_availability(std::time::Instant::now());
+        Ok(BackendConnectionStatus::Connected)
+      },
+      Some(false) => {
+        self.connection_failed();
+        Err(ConnectionError::NoBackendAvailable)
+      },
+    }
+  }
+
+  /// counts a connection that failed after `try_connect` returned it
+  pub fn connection_failed(&mut self) {
+    self.failures += 1;
+    self.retry_policy.fail();
+    // the trial failed, trip_circuit_breaker sets the next cooldown
+    if self.circuit_breaker == CircuitBreakerState::HalfOpen {
+      self.circuit_breaker = CircuitBreakerState::Open(std::time::Instant::now());
+    }
+    self.update_availability(std::time::Instant::now());
+  }
+}
+
+/// exponentially weighted moving average, each new sample counts for a quarter
+fn moving_average(average: Option<std::time::Duration>, sample: std::time::Duration) -> std::time::Duration {
+  match average {
+    Some(average) => (average * 3 + sample) / 4,
+    None          => sample,
+  }
+}
+
+/// length of the window of a RollingCounter, in seconds
+pub const ROLLING_WINDOW: u64 = 60;
+
+/// counts the events of the last minute, in one bucket per second
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct RollingCounter {
+  origin:  std::time::Instant,
+  /// second of the newest bucket, counted from origin
+  last:    u64,
+  buckets: Vec<usize>,
+}
+
+impl RollingCounter {
+  pub fn new(origin: std::time::Instant) -> RollingCounter {
+    RollingCounter {
+      origin,
+      last:    0,
+      buckets: vec![0; ROLLING_WINDOW as usize],
+    }
+  }
+
+  fn second(&self, now: std::time::Instant) -> u64 {
+    if now > self.origin {
+      now.duration_since(self.origin).as_secs()
+    } else {
+      0
+    }
+  }
+
+  pub fn add(&mut self, count: usize, now: std::time::Instant) {
+    let second = self.second(now);
+    if second > self.last {
+      // the buckets of the seconds without events are reused
+      for elapsed in 0..std::cmp::min(second - self.last, ROLLING_WINDOW) {
+        self.buckets[((second - elapsed) % ROLLING_WINDOW) as usize] = 0;
+      }
+      self.last = second;
+    }
+
+    if second + ROLLING_WINDOW > self.last {
+      self.buckets[(second % ROLLING_WINDOW) as usize] += count;
+    }
+  }
+
+  /// events in the minute before `now`
+  pub fn count_at(&self, now: std::time::Instant) -> usize {
+    let second = std::cmp::max(self.second(now), self.last);
+    let first = if second + 1 > ROLLING_WINDOW { second + 1 - ROLLING_WINDOW } else { 0 };
+
+    (first..self.last + 1).map(|second| self.buckets[(second % ROLLING_WINDOW) as usize]).sum()
+  }
 }
 
 #[derive(Clone)]
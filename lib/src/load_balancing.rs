@@ -1,12 +1,17 @@
-use rand::{thread_rng, seq::SliceRandom};
+use rand::{thread_rng, RngCore, seq::SliceRandom};
 
 use Backend;
 
 use std::{ rc::Rc, cell::RefCell };
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
 pub trait LoadBalancingAlgorithm: Debug {
   fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>>;
+
+  /// name reported in logs and traces
+  fn name(&self) -> &'static str {
+    "custom"
+  }
 }
 
 #[derive(Debug)]
@@ -24,6 +29,10 @@ impl LoadBalancingAlgorithm for RoundRobinAlgorithm {
     res
   }
 
+  fn name(&self) -> &'static str {
+    "roundrobin"
+  }
+
 }
 
 impl RoundRobinAlgorithm {
@@ -36,16 +45,41 @@ impl RoundRobinAlgorithm {
 
 }
 
-#[derive(Debug)]
-pub struct RandomAlgorithm;
+/// uses the thread local RNG unless another one is provided, like
+/// a seeded RNG to get reproducible selections
+pub struct RandomAlgorithm {
+  rng: Option<Box<RngCore>>,
+}
+
+impl RandomAlgorithm {
+  pub fn new() -> Self {
+    RandomAlgorithm { rng: None }
+  }
+
+  pub fn with_rng(rng: Box<RngCore>) -> Self {
+    RandomAlgorithm { rng: Some(rng) }
+  }
+}
+
+impl Debug for RandomAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "RandomAlgorithm {{ seeded: {} }}", self.rng.is_some())
+  }
+}
 
 impl LoadBalancingAlgorithm for RandomAlgorithm {
 
   fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
-    let mut rng = thread_rng();
+    let backend = match self.rng {
+      Some(ref mut rng) => (*backends).choose(&mut **rng),
+      None              => (*backends).choose(&mut thread_rng()),
+    };
 
-    (*backends).choose(&mut rng)
-      .map(|backend| (*backend).clone())
+    backend.map(|backend| (*backend).clone())
+  }
+
+  fn name(&self) -> &'static str {
+    "random"
   }
 
 }
@@ -62,13 +96,73 @@ impl LoadBalancingAlgorithm for LeastConnectionsAlgorithm {
       .map(|backend| (*backend).clone())
   }
 
+  fn name(&self) -> &'static str {
+    "leastconnections"
+  }
+
+}
+
+/// picks a backend at random, with a probability proportional to its weight.
+/// Like `RandomAlgorithm`, it can be given its own RNG
+pub struct WeightedRandomAlgorithm {
+  rng: Option<Box<RngCore>>,
+}
+
+impl WeightedRandomAlgorithm {
+  pub fn new() -> Self {
+    WeightedRandomAlgorithm { rng: None }
+  }
+
+  pub fn with_rng(rng: Box<RngCore>) -> Self {
+    WeightedRandomAlgorithm { rng: Some(rng) }
+  }
+}
+
+impl Debug for WeightedRandomAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "WeightedRandomAlgorithm {{ seeded: {} }}", self.rng.is_some())
+  }
+}
+
+impl LoadBalancingAlgorithm for WeightedRandomAlgorithm {
+
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
+    let backend = match self.rng {
+      Some(ref mut rng) => (*backends).choose_weighted(&mut **rng, |backend| backend.borrow().weight() as u32),
+      None              => (*backends).choose_weighted(&mut thread_rng(), |backend| backend.borrow().weight() as u32),
+    };
+
+    backend.ok().map(|backend| (*backend).clone())
+  }
+
+  fn name(&self) -> &'static str {
+    "weightedrandom"
+  }
+
+}
+
+/// always picks the first backend, the list being in configuration order
+#[derive(Debug)]
+pub struct ConfigOrderAlgorithm;
+
+impl LoadBalancingAlgorithm for ConfigOrderAlgorithm {
+
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
+    backends.first().map(|backend| (*backend).clone())
+  }
+
+  fn name(&self) -> &'static str {
+    "configorder"
+  }
+
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
   use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-  use BackendStatus;
+  use std::time::Instant;
+  use {BackendStatus,CircuitBreakerState,RollingCounter};
   use retry::{RetryPolicyWrapper, ExponentialBackoffPolicy};
 
   fn create_backend(id: String, connections: Option<usize>) -> Backend {
@@ -79,9 +173,20 @@ mod test {
       status: BackendStatus::Normal,
       retry_policy: RetryPolicyWrapper::ExponentialBackoff(ExponentialBackoffPolicy::new(1)),
       active_connections: connections.unwrap_or(0),
+      opened_connections: 0,
+      closed_connections: 0,
+      recent_opened_connections: RollingCounter::new(Instant::now()),
+      recent_closed_connections: RollingCounter::new(Instant::now()),
       failures: 0,
       load_balancing_parameters: None,
       backup: false,
+      healthy: None,
+      connect_time: None,
+      request_time: None,
+      flapping_until: None,
+      circuit_breaker: CircuitBreakerState::Closed,
+      available_since: None,
+      rebalanced_weight: None,
     }
   }
 
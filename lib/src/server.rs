@@ -1168,7 +1168,10 @@ impl Server {
       Ok(BackendConnectAction::New) => {
       },
       Err(ConnectionError::HostNotFound) | Err(ConnectionError::NoBackendAvailable) |
-        Err(ConnectionError::HttpsRedirect) | Err(ConnectionError::InvalidHost) => {
+        Err(ConnectionError::HttpsRedirect) | Err(ConnectionError::InvalidHost) |
+        Err(ConnectionError::RetryLater(_)) | Err(ConnectionError::TooManyConnections) |
+        Err(ConnectionError::DeadlineExceeded) | Err(ConnectionError::Reconfiguring) |
+        Err(ConnectionError::TooManyRetries) => {
         if protocol == Protocol::TCP {
           self.close_session(token);
         }
@@ -6,7 +6,7 @@ use std::collections::HashMap;
 use std::os::unix::io::AsRawFd;
 use std::io::ErrorKind;
 use slab::Slab;
-use std::rc::Rc;
+use std::rc::{Rc,Weak};
 use std::cell::RefCell;
 use std::net::{SocketAddr,Shutdown};
 use uuid::Uuid;
@@ -16,7 +16,7 @@ use mio_extras::timer::{Timer,Timeout};
 
 use sozu_command::scm_socket::ScmSocket;
 use sozu_command::config::{ProxyProtocolConfig, LoadBalancingAlgorithms};
-use sozu_command::proxy::{ProxyRequestData,ProxyRequest,ProxyResponse,ProxyResponseStatus,ProxyEvent};
+use sozu_command::proxy::{ProxyRequestData,ProxyRequest,ProxyResponse,ProxyResponseStatus};
 use sozu_command::proxy::TcpListener as TcpListenerConfig;
 use sozu_command::logging;
 use sozu_command::buffer::Buffer;
@@ -26,14 +26,13 @@ use {AppId,Backend,SessionResult,ConnectionError,Protocol,Readiness,SessionMetri
   CloseResult};
 use backends::BackendMap;
 use server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,
-  ListenSession, CONN_RETRIES, push_event};
+  ListenSession, CONN_RETRIES};
 use pool::{Pool,Checkout};
 use socket::server_bind;
 use protocol::{Pipe, ProtocolResult};
 use protocol::proxy_protocol::send::SendProxyProtocol;
 use protocol::proxy_protocol::relay::RelayProxyProtocol;
 use protocol::proxy_protocol::expect::ExpectProxyProtocol;
-use retry::RetryPolicy;
 
 use util::UnwrapLog;
 
@@ -56,6 +55,7 @@ pub struct Session {
   frontend_token:     Token,
   backend_token:      Option<Token>,
   back_connected:     BackendConnectionStatus,
+  backends:           Weak<RefCell<BackendMap>>,
   accept_token:       Token,
   app_id:             Option<String>,
   metrics:            SessionMetrics,
@@ -68,8 +68,9 @@ pub struct Session {
 }
 
 impl Session {
-  fn new(sock: TcpStream, frontend_token: Token, accept_token: Token, front_buf: Checkout<Buffer>,
-    back_buf: Checkout<Buffer>, proxy_protocol: Option<ProxyProtocolConfig>, timeout: Timeout) -> Session {
+  fn new(sock: TcpStream, frontend_token: Token, accept_token: Token, backends: Weak<RefCell<BackendMap>>,
+    front_buf: Checkout<Buffer>, back_buf: Checkout<Buffer>, proxy_protocol: Option<ProxyProtocolConfig>,
+    timeout: Timeout) -> Session {
     let s = sock.try_clone().expect("could not clone the socket");
     let addr = sock.local_addr().ok();
     let mut frontend_buffer = None;
@@ -107,6 +108,7 @@ impl Session {
       frontend_token,
       backend_token:      None,
       back_connected:     BackendConnectionStatus::NotConnected,
+      backends,
       accept_token,
       app_id:             None,
       metrics:            SessionMetrics::new(),
@@ -365,19 +367,17 @@ impl Session {
   }
 
   fn set_back_connected(&mut self, status: BackendConnectionStatus) {
+    let last = self.back_connected;
     self.back_connected = status;
+    // confirmed connections are resolved by poll_backend_connection
+    if last == BackendConnectionStatus::Connecting && status == BackendConnectionStatus::NotConnected {
+      self.backends.upgrade().map(|backends| backends.borrow_mut().connection_attempt_resolved());
+    }
     if status == BackendConnectionStatus::Connected {
       gauge_add!("backend.connections", 1);
       if let Some(State::SendProxyProtocol(ref mut pp)) = self.protocol {
         pp.set_back_connected(BackendConnectionStatus::Connected);
       }
-
-      self.backend.as_ref().map(|backend| {
-        let ref mut backend = *backend.borrow_mut();
-        //successful connection, rest failure counter
-        backend.failures = 0;
-        backend.retry_policy.succeed();
-      });
     }
   }
 
@@ -387,27 +387,34 @@ impl Session {
 
   fn remove_backend(&mut self) {
     if let Some(backend) = self.backend.take() {
+      if self.back_connected == BackendConnectionStatus::Connecting {
+        (*backend.borrow_mut()).connection_abandoned();
+      }
       (*backend.borrow_mut()).dec_connections();
     }
 
     self.backend_token = None;
   }
 
-  fn fail_backend_connection(&mut self) {
-    self.backend.as_ref().map(|backend| {
-      let ref mut backend = *backend.borrow_mut();
-      backend.failures += 1;
+  /// checks the pending connection to the backend, once its socket got an event
+  fn poll_backend_connection(&self) -> Result<BackendConnectionStatus, ConnectionError> {
+    let backend = self.backend.as_ref().ok_or(ConnectionError::NoBackendAvailable)?;
+    let socket = self.back_socket().ok_or(ConnectionError::NoBackendAvailable)?;
 
-      let already_unavailable = backend.retry_policy.is_down();
-      backend.retry_policy.fail();
-      incr!("backend.connections.error");
-      if !already_unavailable && backend.retry_policy.is_down() {
-        error!("backend server {} at {} is down", backend.backend_id, backend.address);
-        incr!("backend.down");
+    match self.backends.upgrade() {
+      Some(backends) => backends.borrow_mut().poll_backend_connection(backend, socket),
+      None           => backend.borrow_mut().poll_connect(socket),
+    }
+  }
 
-        push_event(ProxyEvent::BackendDown(backend.backend_id.clone(), backend.address));
+  /// counts a failed connection to the backend that poll_connect did not see
+  fn backend_connection_failed(&self) {
+    if let Some(ref backend) = self.backend {
+      match self.backends.upgrade() {
+        Some(backends) => backends.borrow_mut().backend_connection_failed(backend),
+        None           => backend.borrow_mut().connection_failed(),
       }
-    });
+    }
   }
 
   fn reset_connection_attempt(&mut self) {
@@ -515,18 +522,33 @@ impl ProxySession for Session {
     self.metrics().service_start();
 
     if self.back_connected() == BackendConnectionStatus::Connecting {
-      if self.back_readiness().unwrap().event.is_hup() {
-        //retry connecting the backend
-        error!("error connecting to backend, trying again");
-        self.metrics().service_stop();
-        self.connection_attempt += 1;
-        self.fail_backend_connection();
-
-        let backend_token = self.backend_token;
-        return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
-      } else if self.back_readiness().unwrap().event != UnixReady::from(Ready::empty()) {
-        self.reset_connection_attempt();
-        self.set_back_connected(BackendConnectionStatus::Connected);
+      if self.back_readiness().unwrap().event != UnixReady::from(Ready::empty()) {
+        let hup = self.back_readiness().unwrap().event.is_hup();
+        match self.poll_backend_connection() {
+          Ok(BackendConnectionStatus::Connected) => {
+            self.reset_connection_attempt();
+            self.set_back_connected(BackendConnectionStatus::Connected);
+          },
+          Ok(BackendConnectionStatus::Connecting) if !hup => {
+            // the event did not complete the connection, wait for the next one
+            self.back_readiness().map(|r| r.event = UnixReady::from(Ready::empty()));
+          },
+          res => {
+            // a hang up before the connection completed leaves no socket
+            // error for poll_connect to see, the failure is counted here
+            if res.is_ok() {
+              self.backend_connection_failed();
+            }
+
+            //retry connecting the backend
+            error!("error connecting to backend, trying again");
+            self.metrics().service_stop();
+            self.connection_attempt += 1;
+
+            let backend_token = self.backend_token;
+            return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
+          },
+        }
       }
     }
 
@@ -850,6 +872,11 @@ impl ProxyConfiguration<Session> for Proxy {
         Ok(BackendConnectAction::New)
       },
       Err(ConnectionError::NoBackendAvailable) => Err(ConnectionError::NoBackendAvailable),
+      Err(ConnectionError::RetryLater(wait)) => Err(ConnectionError::RetryLater(wait)),
+      Err(ConnectionError::TooManyConnections) => Err(ConnectionError::TooManyConnections),
+      Err(ConnectionError::DeadlineExceeded) => Err(ConnectionError::DeadlineExceeded),
+      Err(ConnectionError::Reconfiguring) => Err(ConnectionError::Reconfiguring),
+      Err(ConnectionError::TooManyRetries) => Err(ConnectionError::TooManyRetries),
       Err(e) => {
         panic!("tcp connect_to_backend: unexpected error: {:?}", e);
       }
@@ -955,8 +982,8 @@ impl ProxyConfiguration<Session> for Proxy {
         if let Err(e) = frontend_sock.set_nodelay(true) {
           error!("error setting nodelay on front socket({:?}): {:?}", frontend_sock, e);
         }
-        let c = Session::new(frontend_sock, session_token, internal_token, front_buf, back_buf, proxy_protocol.clone(),
-        timeout);
+        let c = Session::new(frontend_sock, session_token, internal_token, Rc::downgrade(&self.backends),
+        front_buf, back_buf, proxy_protocol.clone(), timeout);
         incr!("tcp.requests");
 
         if let Err(e) = poll.register(